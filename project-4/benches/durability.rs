@@ -0,0 +1,140 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::{BatchSize, Criterion, ParameterizedBenchmark, Throughput};
+use kvs::{Durability, KvStore, KvStoreOptions, KvsEngine, SledKvsEngine};
+use std::thread;
+use tempfile::TempDir;
+
+const WRITES: u32 = 100;
+const WRITERS: u32 = 8;
+
+fn modes() -> Vec<Durability> {
+    vec![
+        Durability::Never,
+        Durability::EveryWrite,
+        Durability::Interval(10),
+        Durability::GroupCommit,
+    ]
+}
+
+fn open_kvs(durability: Durability) -> (TempDir, KvStore) {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions {
+        durability,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    (temp_dir, store)
+}
+
+fn open_sled(durability: Durability) -> (TempDir, SledKvsEngine) {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = SledKvsEngine::open_with_durability(temp_dir.path(), durability).unwrap();
+    (temp_dir, engine)
+}
+
+// How many writes per second each mode sustains.
+fn throughput_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, &durability| {
+            b.iter_batched(
+                || open_kvs(durability),
+                |(_temp_dir, store)| {
+                    for i in 0..WRITES {
+                        store.set(format!("key{}", i), "value".to_owned()).unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        },
+        modes(),
+    )
+    .with_function("sled", |b, &durability| {
+        b.iter_batched(
+            || open_sled(durability),
+            |(_temp_dir, engine)| {
+                for i in 0..WRITES {
+                    engine.set(format!("key{}", i), "value".to_owned()).unwrap();
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    })
+    .throughput(|_| Throughput::Elements(WRITES))
+    .sample_size(10);
+    c.bench("durability_throughput", bench);
+}
+
+/// Makes `WRITES` writes from each of `WRITERS` threads at once.
+fn write_concurrently<E: KvsEngine>(engine: E) {
+    let handles: Vec<_> = (0..WRITERS)
+        .map(|t| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for i in 0..WRITES {
+                    engine.set(format!("key{}-{}", t, i), "value".to_owned()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+// How many writes per second each mode sustains with several writers,
+// which group commit lets share their syncs.
+fn concurrent_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, &durability| {
+            b.iter_batched(
+                || open_kvs(durability),
+                |(_temp_dir, store)| write_concurrently(store),
+                BatchSize::PerIteration,
+            )
+        },
+        modes(),
+    )
+    .with_function("sled", |b, &durability| {
+        b.iter_batched(
+            || open_sled(durability),
+            |(_temp_dir, engine)| write_concurrently(engine),
+            BatchSize::PerIteration,
+        )
+    })
+    .throughput(|_| Throughput::Elements(WRITES * WRITERS))
+    .sample_size(10);
+    c.bench("durability_concurrent", bench);
+}
+
+// How long a single write takes to return in each mode.
+fn latency_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "kvs",
+        |b, &durability| {
+            let (_temp_dir, store) = open_kvs(durability);
+            let mut i = 0;
+            b.iter(|| {
+                i += 1;
+                store.set(format!("key{}", i), "value".to_owned()).unwrap();
+            })
+        },
+        modes(),
+    )
+    .with_function("sled", |b, &durability| {
+        let (_temp_dir, engine) = open_sled(durability);
+        let mut i = 0;
+        b.iter(|| {
+            i += 1;
+            engine.set(format!("key{}", i), "value".to_owned()).unwrap();
+        })
+    })
+    .sample_size(10);
+    c.bench("durability_latency", bench);
+}
+
+criterion_group!(benches, throughput_bench, concurrent_bench, latency_bench);
+criterion_main!(benches);
@@ -0,0 +1,182 @@
+#[macro_use]
+extern crate criterion;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{Bencher, Criterion, ParameterizedBenchmark, Throughput};
+use kvs::thread_pool::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool,
+};
+use kvs::{KvStore, KvsClient, KvsClientOptions, KvsEngine, KvsServer, ServerHandle};
+use tempfile::TempDir;
+
+const CLIENTS: usize = 8;
+const REQUESTS: usize = 100;
+
+const SHORT_JOBS: usize = 10_000;
+const LONG_JOBS: usize = 16;
+const LONG_JOB: Duration = Duration::from_millis(5);
+
+fn thread_counts() -> Vec<u32> {
+    vec![1, 2, 4, 8]
+}
+
+fn start<P: ThreadPool + Send + 'static>(threads: u32) -> (TempDir, ServerHandle) {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = P::new(threads).unwrap();
+    let server = KvsServer::new(engine, pool).start("127.0.0.1:0").unwrap();
+    (temp_dir, server)
+}
+
+fn client(server: &ServerHandle) -> Arc<KvsClient> {
+    // The server holds a worker for as long as a connection is open, until
+    // it goes idle for a second, so pooled connections would keep the
+    // smaller pools waiting on that. Every request gets a connection of
+    // its own instead.
+    let options = KvsClientOptions {
+        pool_size: 0,
+        ..KvsClientOptions::default()
+    };
+    Arc::new(KvsClient::connect_with_options(server.addr(), options).unwrap())
+}
+
+/// Runs `request` `REQUESTS` times from each of `CLIENTS` threads.
+fn load<F>(client: &Arc<KvsClient>, request: F)
+where
+    F: Fn(&KvsClient, usize) + Send + Sync + Copy + 'static,
+{
+    let handles: Vec<_> = (0..CLIENTS)
+        .map(|c| {
+            let client = Arc::clone(client);
+            thread::spawn(move || {
+                for i in 0..REQUESTS {
+                    request(&client, c * REQUESTS + i);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+fn write<P: ThreadPool + Send + 'static>(b: &mut Bencher, threads: u32) {
+    let (_temp_dir, server) = start::<P>(threads);
+    let client = client(&server);
+    b.iter(|| {
+        load(&client, |client, i| {
+            client.set(format!("key{}", i), "value".to_owned()).unwrap()
+        })
+    });
+    drop(client);
+    server.stop(Duration::from_secs(5)).unwrap();
+}
+
+fn read<P: ThreadPool + Send + 'static>(b: &mut Bencher, threads: u32) {
+    let (_temp_dir, server) = start::<P>(threads);
+    let client = client(&server);
+    load(&client, |client, i| {
+        client.set(format!("key{}", i), "value".to_owned()).unwrap()
+    });
+    b.iter(|| {
+        load(&client, |client, i| {
+            assert_eq!(
+                client.get(format!("key{}", i)).unwrap(),
+                Some("value".to_owned())
+            )
+        })
+    });
+    drop(client);
+    server.stop(Duration::from_secs(5)).unwrap();
+}
+
+// Requests per second kvs-server answers on each pool, by pool size. The
+// naive pool ignores its size and starts a thread per connection.
+fn write_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "shared_queue",
+        |b, &threads| write::<SharedQueueThreadPool>(b, threads),
+        thread_counts(),
+    )
+    .with_function("rayon", |b, &threads| write::<RayonThreadPool>(b, threads))
+    .with_function("naive", |b, &threads| write::<NaiveThreadPool>(b, threads))
+    .throughput(|_| Throughput::Elements((CLIENTS * REQUESTS) as u32))
+    .sample_size(10);
+    c.bench("thread_pool_write", bench);
+}
+
+fn read_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "shared_queue",
+        |b, &threads| read::<SharedQueueThreadPool>(b, threads),
+        thread_counts(),
+    )
+    .with_function("rayon", |b, &threads| read::<RayonThreadPool>(b, threads))
+    .with_function("naive", |b, &threads| read::<NaiveThreadPool>(b, threads))
+    .throughput(|_| Throughput::Elements((CLIENTS * REQUESTS) as u32))
+    .sample_size(10);
+    c.bench("thread_pool_read", bench);
+}
+
+/// Runs `jobs` copies of `job` on a pool and waits for all of them.
+fn jobs<P: ThreadPool>(b: &mut Bencher, threads: u32, jobs: usize, job: fn(&AtomicUsize)) {
+    let pool = P::new(threads).unwrap();
+    let counter = AtomicUsize::new(0);
+    b.iter(|| {
+        pool.scope(|scope| {
+            for _ in 0..jobs {
+                let counter = &counter;
+                scope.spawn(move || job(counter));
+            }
+        })
+    });
+}
+
+fn short_job(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Keeps a thread busy, rather than asleep, for `LONG_JOB`.
+fn long_job(counter: &AtomicUsize) {
+    let start = Instant::now();
+    while start.elapsed() < LONG_JOB {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Many tiny jobs stress the queue itself, while a few long ones show how
+// evenly the work is spread over the threads.
+fn short_jobs_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "shared_queue",
+        |b, &threads| jobs::<SharedQueueThreadPool>(b, threads, SHORT_JOBS, short_job),
+        thread_counts(),
+    )
+    .with_function("work_stealing", |b, &threads| {
+        jobs::<WorkStealingThreadPool>(b, threads, SHORT_JOBS, short_job)
+    })
+    .throughput(|_| Throughput::Elements(SHORT_JOBS as u32))
+    .sample_size(10);
+    c.bench("thread_pool_short_jobs", bench);
+}
+
+fn long_jobs_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "shared_queue",
+        |b, &threads| jobs::<SharedQueueThreadPool>(b, threads, LONG_JOBS, long_job),
+        thread_counts(),
+    )
+    .with_function("work_stealing", |b, &threads| {
+        jobs::<WorkStealingThreadPool>(b, threads, LONG_JOBS, long_job)
+    })
+    .throughput(|_| Throughput::Elements(LONG_JOBS as u32))
+    .sample_size(10);
+    c.bench("thread_pool_long_jobs", bench);
+}
+
+criterion_group!(benches, write_bench, read_bench, short_jobs_bench, long_jobs_bench);
+criterion_main!(benches);
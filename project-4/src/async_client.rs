@@ -0,0 +1,152 @@
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::time::Duration;
+
+use futures::{Future, Sink, Stream};
+use tokio::codec::Framed;
+use tokio::net::TcpStream;
+
+use crate::client::unexpected;
+use crate::protocol::{ClientCodec, Request};
+use crate::{KvError, KvsCommand, KvsFuture, KvsResult, Op, Result};
+
+/// A client for `kvs-server` whose calls return futures, to be driven by a
+/// tokio runtime.
+///
+/// It holds a single connection. A call takes the client and hands it
+/// back together with the result, so requests on one client go out one
+/// at a time; more clients give more requests in flight.
+///
+/// Errors the server reports come back in the inner `Result`, with the
+/// client still usable. The future itself only fails when the connection
+/// does, which loses the client.
+pub struct AsyncKvsClient {
+    conn: Framed<TcpStream, ClientCodec>,
+    next_id: u64,
+}
+
+/// What every call resolves to: its result and the client to go on with.
+pub type ClientReply<T> = KvsFuture<(Result<T>, AsyncKvsClient)>;
+
+impl AsyncKvsClient {
+    pub fn connect(addr: SocketAddr) -> KvsFuture<AsyncKvsClient> {
+        Box::new(TcpStream::connect(&addr).from_err().and_then(|stream| {
+            stream.set_nodelay(true)?;
+            Ok(AsyncKvsClient {
+                conn: Framed::new(stream, ClientCodec::default()),
+                next_id: 0,
+            })
+        }))
+    }
+
+    pub fn get(self, key: String) -> ClientReply<Option<String>> {
+        self.call(KvsCommand::Get(key), |result| match result {
+            KvsResult::Some(value) => Ok(Some(value)),
+            KvsResult::None => Ok(None),
+            result => Err(unexpected(result)),
+        })
+    }
+
+    pub fn set(self, key: String, value: String) -> ClientReply<()> {
+        self.set_with_ttl(key, value, None)
+    }
+
+    pub fn set_with_ttl(
+        self,
+        key: String,
+        value: String,
+        ttl: Option<Duration>,
+    ) -> ClientReply<()> {
+        self.call(KvsCommand::Set(key, value, ttl), expect_ok)
+    }
+
+    pub fn remove(self, key: String) -> ClientReply<()> {
+        self.call(KvsCommand::Remove(key), expect_ok)
+    }
+
+    pub fn ttl(self, key: String) -> ClientReply<Option<Duration>> {
+        self.call(KvsCommand::Ttl(key), |result| match result {
+            KvsResult::Ttl(ttl) => Ok(ttl),
+            result => Err(unexpected(result)),
+        })
+    }
+
+    pub fn scan(
+        self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: Option<usize>,
+    ) -> ClientReply<Vec<(String, String)>> {
+        self.call(KvsCommand::Scan(start, end, limit), |result| match result {
+            KvsResult::Pairs(pairs) => Ok(pairs),
+            result => Err(unexpected(result)),
+        })
+    }
+
+    pub fn write_batch(self, ops: Vec<Op>) -> ClientReply<()> {
+        self.call(KvsCommand::Batch(ops), expect_ok)
+    }
+
+    pub fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> ClientReply<bool> {
+        self.call(
+            KvsCommand::CompareAndSwap(key, expected, new),
+            |result| match result {
+                KvsResult::Swapped(swapped) => Ok(swapped),
+                result => Err(unexpected(result)),
+            },
+        )
+    }
+
+    /// Sends `command`, waits for its response and turns the result into
+    /// a `T` with `convert`, unless the server returned an error.
+    fn call<T, F>(self, command: KvsCommand, convert: F) -> ClientReply<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(KvsResult) -> Result<T> + Send + 'static,
+    {
+        let id = self.next_id;
+        let request = Request { id, command };
+        let reply = self
+            .conn
+            .send(request)
+            .and_then(|conn| conn.into_future().map_err(|(err, _)| err))
+            .and_then(move |(response, conn)| {
+                let response = match response {
+                    Some(response) => response,
+                    None => {
+                        return Err(KvError::ProtocolError(
+                            "connection closed by server".to_owned(),
+                        ))
+                    }
+                };
+                if response.id != id {
+                    return Err(KvError::ProtocolError(format!(
+                        "expected response {}, got {}",
+                        id, response.id
+                    )));
+                }
+                let result = match response.result {
+                    KvsResult::Error(err) => Err(err),
+                    result => convert(result),
+                };
+                let client = AsyncKvsClient {
+                    conn,
+                    next_id: id + 1,
+                };
+                Ok((result, client))
+            });
+        Box::new(reply)
+    }
+}
+
+fn expect_ok(result: KvsResult) -> Result<()> {
+    match result {
+        KvsResult::Ok => Ok(()),
+        result => Err(unexpected(result)),
+    }
+}
@@ -0,0 +1,238 @@
+use std::net::{SocketAddr, TcpListener as StdTcpListener, ToSocketAddrs};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError};
+use futures::future::{self, Shared};
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll, Sink, Stream};
+use log::{debug, error, warn};
+use tokio::codec::{Decoder, Encoder, Framed};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::reactor::Handle;
+use tokio::runtime::{self, Runtime};
+
+use crate::protocol::{Response, ServerCodec};
+use crate::resp::{self, RespCodec};
+use crate::server::{execute, Protocol};
+use crate::thread_pool::ThreadPool;
+use crate::{AsyncKvsEngine, KvError, KvsEngine, KvsResult, Result};
+
+type Shutdown = Shared<oneshot::Receiver<()>>;
+
+/// Serves a `KvsEngine` over TCP from a tokio runtime.
+///
+/// A few runtime threads wait on every connection at once, so idle and
+/// slow clients cost no thread of their own. Reading and writing happens
+/// on the runtime; engine calls run on the thread pool, as in
+/// `AsyncKvsEngine`. A request the pool turns away is answered with
+/// `KvError::Busy`. A pool with a bounded queue should reject rather than
+/// block or run the job itself, either of which would do so on a runtime
+/// thread.
+///
+/// Requests on one connection run one after the other, so pipelined
+/// requests see each other's writes, as they do on `KvsServer`.
+pub struct AsyncKvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: AsyncKvsEngine<E, P>,
+    protocol: Protocol,
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> AsyncKvsServer<E, P> {
+    /// Creates a server speaking the framed kvs protocol.
+    pub fn new(engine: E, pool: P) -> AsyncKvsServer<E, P> {
+        AsyncKvsServer {
+            engine: AsyncKvsEngine::new(engine, pool),
+            protocol: Protocol::Kvs,
+        }
+    }
+
+    pub fn protocol(mut self, protocol: Protocol) -> AsyncKvsServer<E, P> {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Listens on `addr` and serves connections on a new runtime until the
+    /// returned handle is stopped.
+    ///
+    /// Binding to port 0 picks a free port, which `AsyncServerHandle::addr`
+    /// reports.
+    pub fn start<A: ToSocketAddrs>(self, addr: A) -> Result<AsyncServerHandle> {
+        let listener = StdTcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let mut runtime = runtime::Builder::new().name_prefix("kvs-async-").build()?;
+        let (stop, shutdown) = oneshot::channel();
+        let shutdown = shutdown.shared();
+        let (open, closed) = channel::bounded(0);
+
+        let engine = self.engine;
+        let protocol = self.protocol;
+        runtime.spawn(future::lazy(move || {
+            let listener = match TcpListener::from_std(listener, &Handle::default()) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    error!("Listen failed: {}", err);
+                    return future::Either::A(future::ok(()));
+                }
+            };
+            let accept = listener.incoming().then(Ok::<_, ()>).for_each({
+                let shutdown = shutdown.clone();
+                move |socket| {
+                    match socket {
+                        Ok(socket) => {
+                            let served = serve_connection(
+                                socket,
+                                engine.clone(),
+                                protocol,
+                                shutdown.clone(),
+                            );
+                            let open = open.clone();
+                            tokio::spawn(served.then(move |_| {
+                                drop(open);
+                                Ok(())
+                            }));
+                        }
+                        Err(err) => error!("Accept failed: {}", err),
+                    }
+                    Ok(())
+                }
+            });
+            // Dropping the listener stops accepting.
+            future::Either::B(accept.select2(shutdown).then(|_| Ok(())))
+        }));
+
+        Ok(AsyncServerHandle {
+            addr,
+            stop,
+            closed,
+            runtime,
+        })
+    }
+}
+
+/// A server started with `AsyncKvsServer::start`.
+pub struct AsyncServerHandle {
+    addr: SocketAddr,
+    stop: oneshot::Sender<()>,
+    // Disconnected once every connection is done.
+    closed: Receiver<()>,
+    runtime: Runtime,
+}
+
+impl AsyncServerHandle {
+    /// The address the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops the server and closes the engine.
+    ///
+    /// No more connections are accepted and no more requests are read.
+    /// Requests that were already read are answered, for at most
+    /// `deadline`; connections still busy after that are cut off.
+    pub fn stop(self, deadline: Duration) -> Result<()> {
+        // Fails only if the accept loop is already gone.
+        let _ = self.stop.send(());
+        match self.closed.recv_timeout(deadline) {
+            Err(RecvTimeoutError::Timeout) => {
+                warn!("Cutting off connections still open at shutdown")
+            }
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => (),
+        }
+        // Dropping the tasks drops the last clones of the engine, and the
+        // pool finishes whatever they left queued before it goes.
+        self.runtime
+            .shutdown_now()
+            .wait()
+            .map_err(|()| KvError::IoError("Runtime failed to shut down".to_owned()))
+    }
+}
+
+fn serve_connection<E, P>(
+    socket: TcpStream,
+    engine: AsyncKvsEngine<E, P>,
+    protocol: Protocol,
+    shutdown: Shutdown,
+) -> Box<dyn Future<Item = (), Error = ()> + Send>
+where
+    E: KvsEngine,
+    P: ThreadPool + Send + Sync + 'static,
+{
+    if let Err(err) = socket.set_nodelay(true) {
+        error!("Connection error: {}", err);
+    }
+    match protocol {
+        Protocol::Kvs => Box::new(serve(
+            socket,
+            ServerCodec::default(),
+            shutdown,
+            move |request| {
+                debug!("Receive: {:?}", request);
+                let id = request.id;
+                let command = request.command;
+                engine
+                    .run(move |engine| Ok(execute(command, engine)))
+                    .then(move |result| {
+                        let result = result.unwrap_or_else(KvsResult::Error);
+                        Ok(Response { id, result })
+                    })
+            },
+        )),
+        Protocol::Resp => Box::new(serve(
+            socket,
+            RespCodec::default(),
+            shutdown,
+            move |request| {
+                debug!("Receive: {:?}", request);
+                engine
+                    .run(move |engine| Ok(resp::execute(request, engine)))
+                    .then(|reply| {
+                        Ok(reply.unwrap_or_else(|err| resp::Value::Error(format!("ERR {}", err))))
+                    })
+            },
+        )),
+    }
+}
+
+/// Answers the requests on `socket` with `handle` until the client hangs
+/// up or the server shuts down.
+fn serve<C, H, F>(
+    socket: TcpStream,
+    codec: C,
+    shutdown: Shutdown,
+    handle: H,
+) -> impl Future<Item = (), Error = ()>
+where
+    C: Decoder<Error = KvError> + Encoder<Error = KvError>,
+    H: FnMut(<C as Decoder>::Item) -> F,
+    F: Future<Item = <C as Encoder>::Item, Error = KvError>,
+{
+    let (responses, requests) = Framed::new(socket, codec).split();
+    let replies = Until {
+        stream: requests,
+        shutdown,
+    }
+    .and_then(handle);
+    // Replies go out together whenever none is ready right away.
+    responses
+        .send_all(replies)
+        .map(|_| ())
+        .map_err(|err| error!("Connection error: {}", err))
+}
+
+/// Ends `stream` once the server shuts down.
+struct Until<S> {
+    stream: S,
+    shutdown: Shutdown,
+}
+
+impl<S: Stream> Stream for Until<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        match self.shutdown.poll() {
+            Ok(Async::NotReady) => self.stream.poll(),
+            // A dropped handle shuts the server down as well.
+            _ => Ok(Async::Ready(None)),
+        }
+    }
+}
@@ -1,38 +1,33 @@
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 
-use kvs::{KvsCommand, KvsResult, Result};
-use std::io::{Read, Write, BufReader, BufRead};
-use std::net::TcpStream;
+use kvs::{KvError, KvsClient, Result};
+use std::ops::Bound;
+use std::time::Duration;
 
 use std::process::exit;
 
-fn exchange(mut stream: TcpStream, command: &KvsCommand) -> () {
-    let data = serde_json::to_string(&command).unwrap();
-    writeln!(stream, "{}", data).unwrap();
-    stream.flush().unwrap();
-
-    let mut reader = BufReader::new(stream);
-    let mut buf = String::new();
+/// Connects to the server named by `--addr`, or exits.
+fn connect(matches: &ArgMatches) -> KvsClient {
+    let addr = matches.value_of("addr").unwrap_or("127.0.0.1:4000");
+    KvsClient::connect(addr).unwrap_or_else(|e| fail(e))
+}
 
-    reader.read_line(&mut buf).unwrap();
-    let result: KvsResult = serde_json::from_str(&buf).unwrap();
+fn fail(e: KvError) -> ! {
+    eprintln!("{}", e);
+    exit(1)
+}
 
-    match result {
-        KvsResult::Ok => {
-            exit(0)
-        }
-        KvsResult::Some(value) => {
-            println!("{}", value);
-            exit(0)
-        }
-        KvsResult::None => {
-            println!("Key not found");
-            exit(0)
-        }
-        KvsResult::Error(e) => {
-            eprintln!("{}", e);
-            exit(1)
-        }
+/// Parses durations like `30s`, `500ms`, `5m` or `2h`. A bare number is in
+/// seconds. Durations too long to count in seconds are refused.
+fn parse_duration(text: &str) -> Option<Duration> {
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let amount: u64 = text[..split].parse().ok()?;
+    match &text[split..] {
+        "ms" => Some(Duration::from_millis(amount)),
+        "" | "s" => Some(Duration::from_secs(amount)),
+        "m" => amount.checked_mul(60).map(Duration::from_secs),
+        "h" => amount.checked_mul(60 * 60).map(Duration::from_secs),
+        _ => None,
     }
 }
 
@@ -55,6 +50,10 @@ fn main() -> Result<()> {
                 .help("Get value from key")
                 .arg(Arg::with_name("key").required(true))
                 .arg(Arg::with_name("value").required(true))
+                .arg(Arg::with_name("ttl")
+                    .long("ttl")
+                    .takes_value(true)
+                    .help("Time until the key expires, like 30s, 5m or 2h"))
                 .arg(Arg::with_name("addr")
                     .long("addr")
                     .takes_value(true)
@@ -69,6 +68,46 @@ fn main() -> Result<()> {
                     .takes_value(true)
                     .help("Server address"))
         )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .help("Set or remove a key only if it has the expected value")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("expected")
+                    .long("expected")
+                    .takes_value(true)
+                    .help("Current value, leave out if the key must be absent"))
+                .arg(Arg::with_name("new")
+                    .long("new")
+                    .takes_value(true)
+                    .help("New value, leave out to remove the key"))
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .takes_value(true)
+                    .help("Server address"))
+        )
+        .subcommand(
+            SubCommand::with_name("ttl")
+                .help("Show how long until a key expires")
+                .arg(Arg::with_name("key").required(true))
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .takes_value(true)
+                    .help("Server address"))
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .help("List keys from start up to, but not including, end")
+                .arg(Arg::with_name("start").required(true))
+                .arg(Arg::with_name("end").required(true))
+                .arg(Arg::with_name("limit")
+                    .long("limit")
+                    .takes_value(true)
+                    .help("Most keys to list"))
+                .arg(Arg::with_name("addr")
+                    .long("addr")
+                    .takes_value(true)
+                    .help("Server address"))
+        )
         .get_matches();
 
     if matches.is_present("V") {
@@ -77,28 +116,90 @@ fn main() -> Result<()> {
 
 
     if let Some(matches) = matches.subcommand_matches("set") {
-        let addr = matches.value_of("addr").unwrap_or("127.0.0.1:4000");
-        let stream = TcpStream::connect(addr).unwrap();
-        if let Some(key) = matches.value_of("key") {
-            if let Some(value) = matches.value_of("value") {
-                exchange(stream, &KvsCommand::Set(key.to_owned(), value.to_owned()))
+        let ttl = match matches.value_of("ttl").map(parse_duration) {
+            Some(Some(ttl)) => Some(ttl),
+            Some(None) => {
+                eprintln!("Invalid ttl");
+                exit(1)
             }
+            None => None,
+        };
+        if let (Some(key), Some(value)) = (matches.value_of("key"), matches.value_of("value")) {
+            connect(matches)
+                .set_with_ttl(key.to_owned(), value.to_owned(), ttl)
+                .unwrap_or_else(|e| fail(e));
+            exit(0)
         }
     }
 
     if let Some(matches) = matches.subcommand_matches("get") {
-        let addr = matches.value_of("addr").unwrap_or("127.0.0.1:4000");
-        let stream = TcpStream::connect(addr).unwrap();
         if let Some(key) = matches.value_of("key") {
-            exchange(stream, &KvsCommand::Get(key.to_owned()))
+            match connect(matches).get(key.to_owned()) {
+                Ok(Some(value)) => println!("{}", value),
+                Ok(None) => println!("Key not found"),
+                Err(e) => fail(e),
+            }
+            exit(0)
         }
     }
 
     if let Some(matches) = matches.subcommand_matches("rm") {
-        let addr = matches.value_of("addr").unwrap_or("127.0.0.1:4000");
-        let stream = TcpStream::connect(addr).unwrap();
         if let Some(key) = matches.value_of("key") {
-            exchange(stream, &KvsCommand::Remove(key.to_owned()))
+            connect(matches)
+                .remove(key.to_owned())
+                .unwrap_or_else(|e| fail(e));
+            exit(0)
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("cas") {
+        if let Some(key) = matches.value_of("key") {
+            let expected = matches.value_of("expected").map(str::to_owned);
+            let new = matches.value_of("new").map(str::to_owned);
+            match connect(matches).compare_and_swap(key.to_owned(), expected, new) {
+                Ok(true) => exit(0),
+                Ok(false) => {
+                    eprintln!("Value changed");
+                    exit(1)
+                }
+                Err(e) => fail(e),
+            }
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("ttl") {
+        if let Some(key) = matches.value_of("key") {
+            match connect(matches).ttl(key.to_owned()) {
+                // Round up, so a key that still exists never shows 0s left.
+                Ok(Some(ttl)) => println!("{}s", ttl.as_millis().div_ceil(1000)),
+                Ok(None) => println!("No expiry"),
+                Err(e) => fail(e),
+            }
+            exit(0)
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("scan") {
+        let limit = match matches.value_of("limit").map(str::parse) {
+            Some(Ok(limit)) => Some(limit),
+            Some(Err(_)) => {
+                eprintln!("Invalid limit");
+                exit(1)
+            }
+            None => None,
+        };
+        if let (Some(start), Some(end)) = (matches.value_of("start"), matches.value_of("end")) {
+            let pairs = connect(matches)
+                .scan(
+                    Bound::Included(start.to_owned()),
+                    Bound::Excluded(end.to_owned()),
+                    limit,
+                )
+                .unwrap_or_else(|e| fail(e));
+            for (key, value) in pairs {
+                println!("{}\t{}", key, value);
+            }
+            exit(0)
         }
     }
 
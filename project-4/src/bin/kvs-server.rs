@@ -1,44 +1,69 @@
 use std::path::Path;
+use std::process::exit;
+use std::time::Duration;
 
 use clap::{App, Arg};
+use signal_hook::iterator::Signals;
+use signal_hook::{SIGINT, SIGTERM};
 
-use kvs::{KvsCommand, KvsResult, KvStore, KvsEngine, SledKvsEngine, Result};
-use std::io::{Read, Write, BufReader, BufRead};
-use std::net::{TcpListener, TcpStream};
-use std::env;
+use kvs::thread_pool::{QueuePolicy, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AsyncKvsServer, KvStore, KvsEngine, KvsServer, MemKvsEngine, Protocol, Result, SledKvsEngine,
+};
 
-fn exchange(mut stream: TcpStream, store: &mut dyn KvsEngine) -> Result<()> {
-    let mut buf = String::new();
-    let mut reader = BufReader::new(&stream);
-    reader.read_line(&mut buf);
-    print!("Receive: {}", buf);
+/// How long requests already read get to finish once asked to shut down.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
 
-    let command: KvsCommand = serde_json::from_str(&buf).unwrap();
+/// How connections are waited on.
+enum Runtime {
+    /// A thread of the pool per connection.
+    Threaded,
+    /// A tokio runtime for all connections, the pool for engine calls.
+    Async,
+}
 
-    let result = match command {
-        KvsCommand::Set(key, value) => match store.set(key.to_owned(), value.to_owned()) {
-            Err(e) => KvsResult::Error(e),
-            _ => KvsResult::Ok,
-        },
-        KvsCommand::Remove(key) => match store.remove(key) {
-            Err(e) => KvsResult::Error(e),
-            _ => KvsResult::Ok,
-        },
-        KvsCommand::Get(key) => match store.get(key) {
-            Ok(v) => match v {
-                Some(value) => KvsResult::Some(value),
-                None => KvsResult::None,
-            },
-            Err(e) => KvsResult::Error(e),
-        },
+/// Serves `engine` until SIGINT or SIGTERM, then shuts down cleanly.
+fn run<E: KvsEngine>(
+    engine: E,
+    addr: &str,
+    protocol: Protocol,
+    queue: Option<(usize, QueuePolicy)>,
+    runtime: Runtime,
+) -> Result<()> {
+    let threads = num_cpus::get() as u32;
+    let pool = match queue {
+        Some((capacity, policy)) => SharedQueueThreadPool::with_queue(threads, capacity, policy)?,
+        None => SharedQueueThreadPool::new(threads)?,
     };
+    let signals = Signals::new([SIGINT, SIGTERM])?;
+    match runtime {
+        Runtime::Threaded => {
+            let server = KvsServer::new(engine, pool)
+                .protocol(protocol)
+                .start(addr)?;
+            wait_for_signal(&signals);
+            server.stop(SHUTDOWN_DEADLINE)
+        }
+        Runtime::Async => {
+            let server = AsyncKvsServer::new(engine, pool)
+                .protocol(protocol)
+                .start(addr)?;
+            wait_for_signal(&signals);
+            server.stop(SHUTDOWN_DEADLINE)
+        }
+    }
+}
 
-    writeln!(stream, "{}", serde_json::to_string(&result).unwrap()).unwrap();
-    stream.flush().unwrap();
-    Ok(())
+fn wait_for_signal(signals: &Signals) {
+    if let Some(signal) = signals.forever().next() {
+        eprintln!("Received signal {}, shutting down", signal);
+    }
 }
 
 fn main() -> Result<()> {
+    // Errors and warnings go to stderr unless RUST_LOG says otherwise.
+    env_logger::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
     let matches = App::new("kvs")
         .version(env!("CARGO_PKG_VERSION"))
         .author("manhtai")
@@ -51,10 +76,37 @@ fn main() -> Result<()> {
         )
         .arg(Arg::with_name("engine")
             .long("engine")
-            .help("KV engine")
+            .help("KV engine, kvs, sled or memory; memory keeps nothing once the server stops")
             .takes_value(true)
             .value_name("engine")
         )
+        .arg(Arg::with_name("protocol")
+            .long("protocol")
+            .help("Wire protocol, kvs or resp")
+            .takes_value(true)
+            .possible_values(&["kvs", "resp"])
+            .value_name("protocol")
+        )
+        .arg(Arg::with_name("runtime")
+            .long("runtime")
+            .help("How connections are served, threaded or async")
+            .takes_value(true)
+            .possible_values(&["threaded", "async"])
+            .value_name("runtime")
+        )
+        .arg(Arg::with_name("queue-capacity")
+            .long("queue-capacity")
+            .help("Most connections, or requests when async, waiting for a thread; unlimited if not given")
+            .takes_value(true)
+            .value_name("capacity")
+        )
+        .arg(Arg::with_name("queue-policy")
+            .long("queue-policy")
+            .help("What to do with connections once the queue is full; only reject when async, and by default then")
+            .takes_value(true)
+            .possible_values(&["block", "reject", "caller-runs"])
+            .value_name("policy")
+        )
         .get_matches();
 
     if matches.is_present("V") {
@@ -62,27 +114,45 @@ fn main() -> Result<()> {
     }
 
     let engine = matches.value_of("engine").unwrap_or("kvs");
-    let mut kvs_;
-    let mut sled_;
-    let mut store: &mut dyn KvsEngine;
-    if engine == "kvs" {
-        kvs_ = KvStore::open(Path::new("."))?;
-        store = &mut kvs_;
-    } else {
-        sled_ = SledKvsEngine::open(Path::new("."))?;
-        store = &mut sled_;
-    }
-
     let addr = matches.value_of("address").unwrap_or("127.0.0.1:4000");
-    let listener = TcpListener::bind(addr).unwrap();
+    let protocol = match matches.value_of("protocol") {
+        Some("resp") => Protocol::Resp,
+        _ => Protocol::Kvs,
+    };
+    let runtime = match matches.value_of("runtime") {
+        Some("async") => Runtime::Async,
+        _ => Runtime::Threaded,
+    };
+    // Blocking or running the job itself would stall a runtime thread and
+    // every connection waiting on it.
+    let policy = match (matches.value_of("queue-policy"), &runtime) {
+        (Some("reject"), _) | (None, Runtime::Async) => QueuePolicy::Reject,
+        (Some(_), Runtime::Async) => {
+            eprintln!("The async runtime only takes the reject queue policy");
+            exit(1)
+        }
+        (Some("caller-runs"), _) => QueuePolicy::CallerRuns,
+        _ => QueuePolicy::Block,
+    };
+    let queue = match matches.value_of("queue-capacity").map(str::parse) {
+        Some(Ok(capacity)) => Some((capacity, policy)),
+        Some(Err(_)) => {
+            eprintln!("Invalid queue capacity");
+            exit(1)
+        }
+        None => None,
+    };
 
     eprintln!(env!("CARGO_PKG_VERSION"));
     eprintln!("Server listen in: {} with engine: {}", addr, engine);
 
-    for stream in listener.incoming() {
-        let mut stream = stream.unwrap();
-        exchange(stream, store);
+    if engine == "kvs" {
+        let engine = KvStore::open(Path::new("."))?;
+        run(engine, addr, protocol, queue, runtime)
+    } else if engine == "memory" {
+        run(MemKvsEngine::new(), addr, protocol, queue, runtime)
+    } else {
+        let engine = SledKvsEngine::open(Path::new("."))?;
+        run(engine, addr, protocol, queue, runtime)
     }
-
-    Ok(())
 }
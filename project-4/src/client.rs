@@ -0,0 +1,196 @@
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::Bound;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::protocol::Connection;
+use crate::{KvError, KvsCommand, KvsResult, Op, Result};
+
+/// Tuning knobs for `KvsClient`.
+#[derive(Debug, Clone, Copy)]
+pub struct KvsClientOptions {
+    /// How long to wait for the server to accept a connection.
+    pub connect_timeout: Option<Duration>,
+    /// How long to wait for a response, `None` to wait forever.
+    pub read_timeout: Option<Duration>,
+    /// How long to wait for a request to be sent, `None` to wait forever.
+    pub write_timeout: Option<Duration>,
+    /// How many idle connections to keep around for later requests.
+    pub pool_size: usize,
+    /// How long a connection may sit in the pool before it is dropped
+    /// rather than reused, `None` to keep it until the server closes it.
+    /// Kept below the server's idle timeout, a request never goes out on
+    /// a connection the server is closing at that very moment.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for KvsClientOptions {
+    fn default() -> Self {
+        KvsClientOptions {
+            connect_timeout: Some(Duration::from_secs(5)),
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            pool_size: 4,
+            idle_timeout: Some(Duration::from_millis(500)),
+        }
+    }
+}
+
+/// A client for `kvs-server`.
+///
+/// It can be shared between threads: every request borrows an idle
+/// connection from a small pool, or opens a new one if there is none, and
+/// gives it back when the response is in. A connection that failed, went
+/// idle for too long or was closed by the server is dropped instead.
+///
+/// Errors the server reports come back as the same `KvError` the engine
+/// returned, so a missing key is `KvError::KeyNotFound` on both sides.
+pub struct KvsClient {
+    addr: SocketAddr,
+    options: KvsClientOptions,
+    /// Idle connections and when they were last used.
+    pool: Mutex<Vec<(Connection, Instant)>>,
+}
+
+impl KvsClient {
+    /// Connects to the server at `addr` with the default options.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<KvsClient> {
+        KvsClient::connect_with_options(addr, KvsClientOptions::default())
+    }
+
+    /// Connects to the server at `addr`. One connection is opened right
+    /// away so an unreachable server is reported here.
+    pub fn connect_with_options<A: ToSocketAddrs>(
+        addr: A,
+        options: KvsClientOptions,
+    ) -> Result<KvsClient> {
+        let addr = match addr.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(KvError::IoError("no address to connect to".to_owned())),
+        };
+        let client = KvsClient {
+            addr,
+            options,
+            pool: Mutex::new(Vec::new()),
+        };
+        let conn = client.open()?;
+        client.release(conn);
+        Ok(client)
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.call(KvsCommand::Get(key))? {
+            KvsResult::Some(value) => Ok(Some(value)),
+            KvsResult::None => Ok(None),
+            result => Err(unexpected(result)),
+        }
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_with_ttl(key, value, None)
+    }
+
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Option<Duration>) -> Result<()> {
+        match self.call(KvsCommand::Set(key, value, ttl))? {
+            KvsResult::Ok => Ok(()),
+            result => Err(unexpected(result)),
+        }
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        match self.call(KvsCommand::Remove(key))? {
+            KvsResult::Ok => Ok(()),
+            result => Err(unexpected(result)),
+        }
+    }
+
+    pub fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        match self.call(KvsCommand::Ttl(key))? {
+            KvsResult::Ttl(ttl) => Ok(ttl),
+            result => Err(unexpected(result)),
+        }
+    }
+
+    pub fn scan(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        match self.call(KvsCommand::Scan(start, end, limit))? {
+            KvsResult::Pairs(pairs) => Ok(pairs),
+            result => Err(unexpected(result)),
+        }
+    }
+
+    pub fn write_batch(&self, ops: Vec<Op>) -> Result<()> {
+        match self.call(KvsCommand::Batch(ops))? {
+            KvsResult::Ok => Ok(()),
+            result => Err(unexpected(result)),
+        }
+    }
+
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        match self.call(KvsCommand::CompareAndSwap(key, expected, new))? {
+            KvsResult::Swapped(swapped) => Ok(swapped),
+            result => Err(unexpected(result)),
+        }
+    }
+
+    /// Sends `command` on a pooled connection and turns an error result
+    /// into an `Err`.
+    fn call(&self, command: KvsCommand) -> Result<KvsResult> {
+        let mut conn = match self.pooled() {
+            Some(conn) => conn,
+            None => self.open()?,
+        };
+        let result = conn.call(command)?;
+        self.release(conn);
+        match result {
+            KvsResult::Error(err) => Err(err),
+            result => Ok(result),
+        }
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let stream = match self.options.connect_timeout {
+            Some(timeout) => TcpStream::connect_timeout(&self.addr, timeout)?,
+            None => TcpStream::connect(self.addr)?,
+        };
+        stream.set_read_timeout(self.options.read_timeout)?;
+        stream.set_write_timeout(self.options.write_timeout)?;
+        stream.set_nodelay(true)?;
+        Connection::from_stream(stream)
+    }
+
+    /// Takes the most recently used idle connection that is still open.
+    fn pooled(&self) -> Option<Connection> {
+        let mut pool = self.pool.lock().unwrap();
+        while let Some((conn, idle_since)) = pool.pop() {
+            let expired = match self.options.idle_timeout {
+                Some(timeout) => idle_since.elapsed() >= timeout,
+                None => false,
+            };
+            if !expired && !conn.is_closed() {
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    fn release(&self, conn: Connection) {
+        let mut pool = self.pool.lock().unwrap();
+        if pool.len() < self.options.pool_size {
+            pool.push((conn, Instant::now()));
+        }
+    }
+}
+
+pub(crate) fn unexpected(result: KvsResult) -> KvError {
+    KvError::ProtocolError(format!("unexpected response {:?}", result))
+}
@@ -0,0 +1,125 @@
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::sync::oneshot;
+use futures::{future, Future};
+
+use crate::thread_pool::ThreadPool;
+use crate::{KvError, KvsEngine, KvsFuture, Op, Result};
+
+/// A `KvsEngine` whose calls return futures.
+///
+/// Every call runs on a thread of `P` against a clone of the engine, so a
+/// task on an async runtime never blocks on disk I/O; it waits on the
+/// returned future instead. When the pool's queue is full the future
+/// fails with `KvError::Busy`.
+///
+/// Clones share both the engine and the pool.
+pub struct AsyncKvsEngine<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: Arc<P>,
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> AsyncKvsEngine<E, P> {
+    pub fn new(engine: E, pool: P) -> AsyncKvsEngine<E, P> {
+        AsyncKvsEngine {
+            engine,
+            pool: Arc::new(pool),
+        }
+    }
+
+    /// Opens the engine at `path` and a pool of `threads` threads to run
+    /// it on.
+    pub fn open(path: &Path, threads: u32) -> Result<AsyncKvsEngine<E, P>> {
+        Ok(AsyncKvsEngine::new(E::open(path)?, P::new(threads)?))
+    }
+
+    /// The engine the calls run against.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    pub fn set(&self, key: String, value: String) -> KvsFuture<()> {
+        self.set_with_ttl(key, value, None)
+    }
+
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Option<Duration>) -> KvsFuture<()> {
+        self.run(move |engine| engine.set_with_ttl(key, value, ttl))
+    }
+
+    pub fn get(&self, key: String) -> KvsFuture<Option<String>> {
+        self.run(move |engine| engine.get(key))
+    }
+
+    pub fn ttl(&self, key: String) -> KvsFuture<Option<Duration>> {
+        self.run(move |engine| engine.ttl(key))
+    }
+
+    pub fn remove(&self, key: String) -> KvsFuture<()> {
+        self.run(move |engine| engine.remove(key))
+    }
+
+    pub fn write_batch(&self, ops: Vec<Op>) -> KvsFuture<()> {
+        self.run(move |engine| engine.write_batch(ops))
+    }
+
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> KvsFuture<bool> {
+        self.run(move |engine| engine.compare_and_swap(key, expected, new))
+    }
+
+    pub fn scan(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: Option<usize>,
+    ) -> KvsFuture<Vec<(String, String)>> {
+        self.run(move |engine| engine.scan(start, end, limit))
+    }
+
+    pub fn scan_prefix(
+        &self,
+        prefix: String,
+        limit: Option<usize>,
+    ) -> KvsFuture<Vec<(String, String)>> {
+        self.run(move |engine| engine.scan_prefix(prefix, limit))
+    }
+
+    /// Runs `call` against the engine on the pool and resolves to what it
+    /// returns, for work that takes more than one engine call.
+    pub fn run<T, F>(&self, call: F) -> KvsFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&E) -> Result<T> + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        let (sender, receiver) = oneshot::channel();
+        let spawned = self.pool.try_spawn(move || {
+            // The caller may have stopped waiting.
+            let _ = sender.send(call(&engine));
+        });
+        if let Err(err) = spawned {
+            return Box::new(future::err(err));
+        }
+        Box::new(receiver.then(|received| match received {
+            Ok(result) => result,
+            // The job dropped its sender without sending, so it panicked.
+            Err(oneshot::Canceled) => Err(KvError::IoError("Engine call panicked".to_owned())),
+        }))
+    }
+}
+
+impl<E: KvsEngine, P: ThreadPool> Clone for AsyncKvsEngine<E, P> {
+    fn clone(&self) -> AsyncKvsEngine<E, P> {
+        AsyncKvsEngine {
+            engine: self.engine.clone(),
+            pool: Arc::clone(&self.pool),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use log::error;
+
+use super::periodic::Periodic;
+use crate::Result;
+
+/// When a write is forced from the OS to the disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// Leave it to the OS. Writes survive the process crashing, not the
+    /// machine.
+    Never,
+    /// Sync before every write returns.
+    EveryWrite,
+    /// Sync in the background every given number of milliseconds.
+    Interval(u64),
+    /// Sync before every write returns, but let writers that are waiting
+    /// at the same time share a single sync.
+    GroupCommit,
+}
+
+type SyncFn = dyn Fn() -> Result<()> + Send + Sync;
+
+/// Applies a `Durability` policy on top of whatever makes an engine's
+/// writes durable.
+pub(super) struct Syncer {
+    durability: Durability,
+    sync: Arc<SyncFn>,
+    group: GroupCommit,
+    interval: Option<Periodic>,
+    syncs: Arc<AtomicU64>,
+}
+
+impl Syncer {
+    pub fn new<F>(durability: Durability, sync: F) -> Syncer
+    where
+        F: Fn() -> Result<()> + Send + Sync + 'static,
+    {
+        let syncs = Arc::new(AtomicU64::new(0));
+        let sync: Arc<SyncFn> = {
+            let syncs = Arc::clone(&syncs);
+            Arc::new(move || {
+                syncs.fetch_add(1, Ordering::Relaxed);
+                sync()
+            })
+        };
+        let interval = match durability {
+            Durability::Interval(ms) => {
+                let sync = Arc::clone(&sync);
+                Some(Periodic::start(Duration::from_millis(ms), move || {
+                    if let Err(err) = sync() {
+                        error!("Background sync failed: {}", err);
+                    }
+                }))
+            }
+            _ => None,
+        };
+        Syncer {
+            durability,
+            sync,
+            group: GroupCommit::default(),
+            interval,
+            syncs,
+        }
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Records that one more write has reached the OS. Call it in the same
+    /// order the writes were made.
+    pub fn written(&self) -> u64 {
+        self.group.written()
+    }
+
+    /// Returns once write number `ticket` is as durable as the policy asks.
+    ///
+    /// Call it without holding whatever lock orders the writes, or writers
+    /// can't pile up behind a sync to share the next one.
+    pub fn commit(&self, ticket: u64) -> Result<()> {
+        match self.durability {
+            Durability::Never | Durability::Interval(_) => Ok(()),
+            Durability::EveryWrite => (self.sync)(),
+            Durability::GroupCommit => self.group.wait(ticket, &*self.sync),
+        }
+    }
+
+    /// How many syncs have been started so far.
+    pub fn syncs(&self) -> u64 {
+        self.syncs.load(Ordering::Relaxed)
+    }
+
+    /// Syncs everything written so far, whatever the policy.
+    pub fn sync_now(&self) -> Result<()> {
+        (self.sync)()
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        self.interval.take();
+        if self.durability != Durability::Never {
+            if let Err(err) = (self.sync)() {
+                error!("Final sync failed: {}", err);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct GroupCommit {
+    state: Mutex<CommitState>,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct CommitState {
+    written: u64,
+    synced: u64,
+    syncing: bool,
+}
+
+impl GroupCommit {
+    fn written(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        state.written
+    }
+
+    /// Whoever finds no sync in progress becomes the leader and syncs on
+    /// behalf of everything written so far; the rest wait for it.
+    fn wait(&self, ticket: u64, sync: &SyncFn) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+
+            state.syncing = true;
+            let target = state.written;
+            drop(state);
+            let result = sync();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() && target > state.synced {
+                state.synced = target;
+            }
+            self.synced.notify_all();
+            result?;
+        }
+    }
+}
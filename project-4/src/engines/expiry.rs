@@ -0,0 +1,33 @@
+//! Time-to-live bookkeeping shared by the engines.
+//!
+//! Expiry times are stored as absolute milliseconds since the Unix epoch,
+//! so a key keeps its deadline across restarts.
+
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often expired keys are swept out when nothing else says otherwise.
+pub(super) const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+pub(super) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// The expiry time of a key set now with the given time-to-live. One too
+/// long to count in milliseconds never comes.
+pub(super) fn deadline(ttl: Duration) -> u64 {
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    now().saturating_add(ttl)
+}
+
+pub(super) fn is_expired(deadline: u64) -> bool {
+    deadline <= now()
+}
+
+/// How long is left until `deadline`.
+pub(super) fn remaining(deadline: u64) -> Duration {
+    Duration::from_millis(deadline.saturating_sub(now()))
+}
@@ -0,0 +1,958 @@
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::atomic::AtomicCell;
+use crossbeam_skiplist::SkipMap;
+use log::error;
+
+use super::durability::{Durability, Syncer};
+use super::expiry::{self, SWEEP_INTERVAL};
+use super::is_empty_range;
+use super::manifest::{legacy_engine, Manifest};
+use super::migration::{legacy_version, migrate, FORMAT_VERSION};
+use super::periodic::Periodic;
+use super::record::{ReadResult, Record};
+use crate::{KvError, KvsEngine, Op, Result};
+
+const ENGINE: &str = "kvs";
+
+/// Below this many stale bytes the ratio check never triggers a compaction,
+/// otherwise a handful of overwrites in a tiny store would keep compacting.
+const MIN_RATIO_COMPACTION_BYTES: u64 = 64 * 1024;
+
+/// Tuning knobs for `KvStore`.
+#[derive(Debug, Clone, Copy)]
+pub struct KvStoreOptions {
+    /// Compact once this many bytes in the log belong to overwritten or
+    /// removed entries.
+    pub compaction_threshold: u64,
+    /// Also compact once stale bytes reach this multiple of the live bytes.
+    pub compaction_ratio: Option<f64>,
+    /// When writes are synced to disk.
+    pub durability: Durability,
+    /// How often a background thread drops expired keys from the index.
+    pub sweep_interval: Duration,
+}
+
+impl KvStoreOptions {
+    fn parameters(&self) -> BTreeMap<String, String> {
+        let mut parameters = BTreeMap::new();
+        parameters.insert(
+            "compaction_threshold".to_owned(),
+            self.compaction_threshold.to_string(),
+        );
+        if let Some(ratio) = self.compaction_ratio {
+            parameters.insert("compaction_ratio".to_owned(), ratio.to_string());
+        }
+        parameters
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction_threshold: 1024 * 1024,
+            compaction_ratio: None,
+            durability: Durability::Never,
+            sweep_interval: SWEEP_INTERVAL,
+        }
+    }
+}
+
+/// Where a command lives on disk: the log generation it was written to,
+/// the offset of its first byte and its length in bytes. Expiring entries
+/// also keep their expiry time here so `get` doesn't have to read it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CommandPos {
+    gen: u64,
+    pos: u64,
+    len: u64,
+    expires_at: Option<u64>,
+}
+
+impl CommandPos {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(expiry::is_expired)
+    }
+}
+
+/// Where each key's value is. A key that is set again gets its position
+/// swapped in place: replacing the map entry would leave a moment in which
+/// readers find no entry at all.
+type Entries = SkipMap<String, AtomicCell<CommandPos>>;
+
+/// The positions of all live keys, with the byte counts compaction goes
+/// by.
+///
+/// Readers look keys up in `entries` without taking any lock. Everything
+/// that changes the index, writes, compaction and sweeping out expired
+/// keys, holds the writer's lock, which keeps the counts in step.
+struct Index {
+    entries: Arc<Entries>,
+    // Bytes in the log taken up by entries that compaction would drop.
+    uncompacted: u64,
+    // Bytes in the log taken up by entries the index points at.
+    live: u64,
+    // Entries with an expiry time by deadline, so sweeping only looks at
+    // the ones that are due.
+    expiring: BTreeSet<(u64, String)>,
+}
+
+impl Index {
+    fn new() -> Index {
+        Index {
+            entries: Arc::new(SkipMap::new()),
+            uncompacted: 0,
+            live: 0,
+            expiring: BTreeSet::new(),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<CommandPos> {
+        lookup(&self.entries, key)
+    }
+
+    fn insert(&mut self, key: String, cmd_pos: CommandPos) {
+        self.live += cmd_pos.len;
+        let old_pos = self
+            .entries
+            .get(&key)
+            .map(|entry| entry.value().swap(cmd_pos));
+        // Forgotten first, in case the new position has the same deadline.
+        if let Some(old_pos) = old_pos {
+            self.forget(&key, old_pos);
+        }
+        if let Some(deadline) = cmd_pos.expires_at {
+            self.expiring.insert((deadline, key.clone()));
+        }
+        if old_pos.is_none() {
+            self.entries.insert(key, AtomicCell::new(cmd_pos));
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        let old_pos = self.entries.remove(key).map(|entry| entry.value().load());
+        if let Some(old_pos) = old_pos {
+            self.forget(key, old_pos);
+        }
+    }
+
+    fn forget(&mut self, key: &str, old_pos: CommandPos) {
+        self.live -= old_pos.len;
+        self.uncompacted += old_pos.len;
+        if let Some(deadline) = old_pos.expires_at {
+            self.expiring.remove(&(deadline, key.to_owned()));
+        }
+    }
+
+    /// Drops every expired entry, leaving its bytes to compaction.
+    ///
+    /// Only the entries that are due are looked at, in deadline order, so
+    /// this is cheap however many keys there are.
+    fn sweep(&mut self) {
+        let now = expiry::now();
+        while let Some(due) = self.expiring.first().cloned() {
+            if due.0 > now {
+                break;
+            }
+            self.expiring.remove(&due);
+            let (deadline, key) = due;
+            let current = self.entries.get(&key).map(|entry| entry.value().load());
+            if current.and_then(|cmd_pos| cmd_pos.expires_at) == Some(deadline) {
+                self.remove(&key);
+            }
+        }
+    }
+}
+
+/// Looks `key` up, treating an expired entry as gone.
+fn lookup(entries: &Entries, key: &str) -> Option<CommandPos> {
+    entries
+        .get(key)
+        .map(|entry| entry.value().load())
+        .filter(|cmd_pos| !cmd_pos.is_expired())
+}
+
+/// What a finished background compaction hands back to the store.
+struct Compacted {
+    gen: u64,
+    moved: Vec<(String, CommandPos, CommandPos)>,
+}
+
+/// A compaction running on its own thread.
+struct Compaction {
+    handle: JoinHandle<()>,
+    receiver: Receiver<Result<Compacted>>,
+}
+
+/// A log-structured key/value store.
+///
+/// Only the position of each value is kept in memory, in key order so that
+/// ranges can be scanned; the value itself is read back from the log
+/// whenever it is asked for.
+///
+/// The log is split into generations, one `<gen>.log` file each. Writes
+/// always go to the newest generation; compaction copies the live entries
+/// of all older generations into a new one on a background thread.
+///
+/// Clones share the same store and can be used from different threads.
+/// Reads go through files of each clone's own and never wait for each
+/// other or for writes; writes take turns on a single writer, but wait for
+/// their sync after letting go of it.
+#[derive(Clone)]
+pub struct KvStore {
+    entries: Arc<Entries>,
+    reader: KvStoreReader,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    syncer: Arc<Syncer>,
+    // Drops expired keys until the last clone goes away.
+    _sweeper: Arc<Periodic>,
+}
+
+impl KvStore {
+    /// Opens the store at `path` with the given options.
+    ///
+    /// Directories written by an older version of `KvStore` are upgraded
+    /// in place first.
+    pub fn open_with_options(path: &Path, options: KvStoreOptions) -> Result<KvStore> {
+        let path = Arc::new(path.to_path_buf());
+        fs::create_dir_all(&*path)?;
+
+        // A compaction or migration that never got renamed into place is
+        // incomplete.
+        for entry in fs::read_dir(&*path)? {
+            let entry_path = entry?.path();
+            let extension = entry_path.extension();
+            if extension == Some(OsStr::new("compact")) || extension == Some(OsStr::new("migrate")) {
+                fs::remove_file(entry_path)?;
+            }
+        }
+
+        let mut manifest = match Manifest::load(&path)? {
+            Some(manifest) => manifest,
+            None => {
+                let has_logs = !sorted_gen_list(&path)?.is_empty();
+                let version = match legacy_engine(&path, has_logs)? {
+                    Some(ENGINE) => legacy_version(&path)?,
+                    Some(engine) => {
+                        return Err(KvError::WrongEngine {
+                            expected: ENGINE.to_owned(),
+                            found: engine.to_owned(),
+                        })
+                    }
+                    None => FORMAT_VERSION,
+                };
+                let manifest = Manifest::new(ENGINE, version, options.parameters());
+                manifest.save(&path)?;
+                manifest
+            }
+        };
+        manifest.check_engine(ENGINE)?;
+        if manifest.format_version == 0 || manifest.format_version > FORMAT_VERSION {
+            return Err(KvError::UnsupportedVersion {
+                engine: ENGINE.to_owned(),
+                version: manifest.format_version,
+            });
+        }
+        migrate(&path, &mut manifest)?;
+
+        let mut index = Index::new();
+
+        let gen_list = sorted_gen_list(&path)?;
+        for &gen in &gen_list {
+            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
+            let end = load(gen, &mut reader, &mut index)?;
+
+            let file_len = reader.reader.get_ref().metadata()?.len();
+            if end < file_len {
+                // Only the newest generation can have been cut short by a
+                // crash; anything missing from an older one is damage.
+                if Some(&gen) != gen_list.last() {
+                    return Err(KvError::Corrupted { gen, offset: end });
+                }
+                OpenOptions::new()
+                    .write(true)
+                    .open(log_path(&path, gen))?
+                    .set_len(end)?;
+            }
+        }
+
+        let gen = gen_list.last().unwrap_or(&0) + 1;
+        let writer = new_log_file(&path, gen)?;
+        let active = Arc::new(Mutex::new(writer.writer.get_ref().try_clone()?));
+        let syncer = {
+            let active = Arc::clone(&active);
+            Arc::new(Syncer::new(options.durability, move || {
+                active.lock().unwrap().sync_data()?;
+                Ok(())
+            }))
+        };
+
+        let entries = Arc::clone(&index.entries);
+        let safe_point = Arc::new(AtomicU64::new(0));
+        let reader = KvStoreReader {
+            path: Arc::clone(&path),
+            safe_point: Arc::clone(&safe_point),
+            readers: RefCell::new(BTreeMap::new()),
+        };
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
+            path,
+            options,
+            gen,
+            index,
+            writer,
+            compaction: None,
+            active,
+            syncer: Arc::clone(&syncer),
+            safe_point,
+        }));
+        let sweeper = {
+            let writer = Arc::clone(&writer);
+            Periodic::start(options.sweep_interval, move || {
+                writer.lock().unwrap().index.sweep()
+            })
+        };
+
+        Ok(KvStore {
+            entries,
+            reader,
+            writer,
+            syncer,
+            _sweeper: Arc::new(sweeper),
+        })
+    }
+
+    /// How many times the log has been synced since the store was opened.
+    pub fn syncs(&self) -> u64 {
+        self.syncer.syncs()
+    }
+
+    /// Reads the value `key` has at `cmd_pos`, looking the key up again
+    /// if a compaction moved it in the meantime.
+    fn read_key(&self, key: &str, mut cmd_pos: CommandPos) -> Result<Option<String>> {
+        loop {
+            if let Some(value) = self.reader.read_value(cmd_pos)? {
+                return Ok(Some(value));
+            }
+            cmd_pos = match lookup(&self.entries, key) {
+                Some(cmd_pos) => cmd_pos,
+                None => return Ok(None),
+            };
+        }
+    }
+}
+
+/// Reads values from the log through files opened by and for one clone
+/// of the store.
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    // Generations below this one have been compacted away.
+    safe_point: Arc<AtomicU64>,
+    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> KvStoreReader {
+        KvStoreReader {
+            path: Arc::clone(&self.path),
+            safe_point: Arc::clone(&self.safe_point),
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl KvStoreReader {
+    /// Reads the value of the `Set` record at `cmd_pos`, or `None` if its
+    /// generation was compacted away after `cmd_pos` was looked up.
+    fn read_value(&self, cmd_pos: CommandPos) -> Result<Option<String>> {
+        let mut readers = self.readers.borrow_mut();
+        // Files of compacted generations are only kept open until here.
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        *readers = readers.split_off(&safe_point);
+
+        let reader = match readers.entry(cmd_pos.gen) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(entry) => {
+                match File::open(log_path(&self.path, cmd_pos.gen)) {
+                    Ok(file) => entry.insert(BufReaderWithPos::new(file)?),
+                    // The safe point moves before files are removed.
+                    Err(ref err)
+                        if err.kind() == io::ErrorKind::NotFound
+                            && cmd_pos.gen < self.safe_point.load(Ordering::SeqCst) =>
+                    {
+                        return Ok(None)
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        };
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        match Record::read_from(&mut reader.take(cmd_pos.len))? {
+            ReadResult::Record(Record::Set(_, value, _), _) => Ok(Some(value)),
+            _ => Err(KvError::Corrupted {
+                gen: cmd_pos.gen,
+                offset: cmd_pos.pos,
+            }),
+        }
+    }
+}
+
+/// The one writer of a store, shared by all its clones.
+struct KvStoreWriter {
+    path: Arc<PathBuf>,
+    options: KvStoreOptions,
+    gen: u64,
+    index: Index,
+    writer: BufWriterWithPos<File>,
+    compaction: Option<Compaction>,
+    // The file behind `writer`, for syncing it outside of the writer.
+    active: Arc<Mutex<File>>,
+    syncer: Arc<Syncer>,
+    safe_point: Arc<AtomicU64>,
+}
+
+/// The write methods return the ticket to pass to `Syncer::commit` once
+/// the writer is let go of.
+impl KvStoreWriter {
+    fn set(&mut self, key: String, value: String, ttl: Option<Duration>) -> Result<u64> {
+        let record = Record::Set(key, value, ttl.map(expiry::deadline));
+        let (cmd_pos, ticket) = self.append(&record)?;
+        if let Record::Set(key, _, _) = record {
+            self.index.insert(key, cmd_pos);
+        }
+        self.save()?;
+        Ok(ticket)
+    }
+
+    fn remove(&mut self, key: String) -> Result<u64> {
+        if self.index.get(&key).is_none() {
+            return Err(KvError::KeyNotFound);
+        }
+
+        let record = Record::Remove(key);
+        let (cmd_pos, ticket) = self.append(&record)?;
+        if let Record::Remove(key) = &record {
+            self.index.remove(key);
+            // The removal itself is stale as soon as nothing older is left
+            // to hide.
+            self.index.uncompacted += cmd_pos.len;
+        }
+        self.save()?;
+        Ok(ticket)
+    }
+
+    /// Returns `None` for an empty batch, which writes nothing.
+    fn write_batch(&mut self, ops: Vec<Op>) -> Result<Option<u64>> {
+        if ops.is_empty() {
+            return Ok(None);
+        }
+        // Nothing is written unless every removal in the batch finds its
+        // key, counting the keys set earlier in the batch.
+        let mut present = HashMap::new();
+        for op in &ops {
+            match op {
+                Op::Set(key, _, _) => {
+                    present.insert(key.as_str(), true);
+                }
+                Op::Remove(key) => {
+                    let exists = match present.get(key.as_str()) {
+                        Some(&exists) => exists,
+                        None => self.index.get(key).is_some(),
+                    };
+                    if !exists {
+                        return Err(KvError::KeyNotFound);
+                    }
+                    present.insert(key.as_str(), false);
+                }
+            }
+        }
+
+        let mut records = Vec::with_capacity(ops.len() + 1);
+        records.push(Record::Batch(ops.len() as u32));
+        for op in ops {
+            records.push(match op {
+                Op::Set(key, value, ttl) => Record::Set(key, value, ttl.map(expiry::deadline)),
+                Op::Remove(key) => Record::Remove(key),
+            });
+        }
+        let (positions, ticket) = self.append_all(&records)?;
+
+        for (record, cmd_pos) in records.into_iter().zip(positions) {
+            match record {
+                Record::Set(key, _, _) => self.index.insert(key, cmd_pos),
+                Record::Remove(key) => {
+                    self.index.remove(&key);
+                    self.index.uncompacted += cmd_pos.len;
+                }
+                // Like a removal, the batch header is only needed until
+                // compaction.
+                Record::Batch(_) => self.index.uncompacted += cmd_pos.len,
+            }
+        }
+        self.save()?;
+        Ok(Some(ticket))
+    }
+
+    /// Appends `record` to the log and returns where it was written, along
+    /// with its sync ticket.
+    fn append(&mut self, record: &Record) -> Result<(CommandPos, u64)> {
+        let (positions, ticket) = self.append_all(slice::from_ref(record))?;
+        Ok((positions[0], ticket))
+    }
+
+    /// Appends `records` back to back under a single sync ticket.
+    fn append_all(&mut self, records: &[Record]) -> Result<(Vec<CommandPos>, u64)> {
+        let mut positions = Vec::with_capacity(records.len());
+        for record in records {
+            let pos = self.writer.pos;
+            let len = record.write_to(&mut self.writer)?;
+            let expires_at = match record {
+                Record::Set(_, _, expires_at) => *expires_at,
+                _ => None,
+            };
+            positions.push(CommandPos {
+                gen: self.gen,
+                pos,
+                len,
+                expires_at,
+            });
+        }
+        self.writer.flush()?;
+        Ok((positions, self.syncer.written()))
+    }
+
+    /// Starts a compaction once enough of the log is stale.
+    fn save(&mut self) -> Result<()> {
+        self.finish_compaction(false)?;
+
+        if self.compaction.is_none() && self.should_compact() {
+            self.start_compaction()?;
+        }
+        Ok(())
+    }
+
+    fn should_compact(&self) -> bool {
+        let index = &self.index;
+        if index.uncompacted >= self.options.compaction_threshold {
+            return true;
+        }
+        match self.options.compaction_ratio {
+            Some(ratio) => {
+                index.uncompacted >= MIN_RATIO_COMPACTION_BYTES
+                    && index.uncompacted as f64 >= ratio * index.live as f64
+            }
+            None => false,
+        }
+    }
+
+    /// Reserves a generation for the compacted log, moves the writer past it
+    /// and copies the live entries on a background thread.
+    ///
+    /// The copy only needs the positions of the entries, not the store, so
+    /// writes carry on in the new generation while it runs.
+    fn start_compaction(&mut self) -> Result<()> {
+        let compaction_gen = self.gen + 1;
+        self.gen += 2;
+        // Whatever the old generation still owes the disk is paid before
+        // the syncer moves on to the new one.
+        if self.syncer.durability() != Durability::Never {
+            self.syncer.sync_now()?;
+        }
+        self.writer = new_log_file(&self.path, self.gen)?;
+        *self.active.lock().unwrap() = self.writer.writer.get_ref().try_clone()?;
+
+        // Expired entries are left behind with the generations they're in.
+        self.index.sweep();
+        // Everything stale so far lives in the generations being replaced.
+        self.index.uncompacted = 0;
+        let snapshot: Vec<(String, CommandPos)> = self
+            .index
+            .entries
+            .iter()
+            .map(|entry| (entry.key().to_owned(), entry.value().load()))
+            .collect();
+
+        let path = Arc::clone(&self.path);
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let result = compact(&path, compaction_gen, snapshot);
+            sender.send(result).ok();
+        });
+
+        self.compaction = Some(Compaction { handle, receiver });
+        Ok(())
+    }
+
+    /// Switches the index over to a finished compaction and removes the
+    /// generations it replaced. With `wait` set this blocks until the
+    /// running compaction is done.
+    fn finish_compaction(&mut self, wait: bool) -> Result<()> {
+        let result = match &self.compaction {
+            Some(compaction) if wait => compaction
+                .receiver
+                .recv()
+                .map_err(|_| TryRecvError::Disconnected),
+            Some(compaction) => compaction.receiver.try_recv(),
+            None => return Ok(()),
+        };
+        let result = match result {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return Ok(()),
+            Err(TryRecvError::Disconnected) => {
+                Err(KvError::IoError("Compaction thread died".to_owned()))
+            }
+        };
+        if let Some(compaction) = self.compaction.take() {
+            compaction.handle.join().ok();
+        }
+
+        let Compacted { gen: compacted_gen, moved } = result?;
+        for (key, old_pos, new_pos) in moved {
+            // Entries written or swept while the compaction ran already point
+            // past it.
+            if let Some(entry) = self.index.entries.get(&key) {
+                if entry.value().load() == old_pos {
+                    entry.value().store(new_pos);
+                }
+            }
+        }
+
+        // Readers that still find an older position now know to look again.
+        self.safe_point.store(compacted_gen, Ordering::SeqCst);
+        for stale_gen in sorted_gen_list(&self.path)? {
+            if stale_gen < compacted_gen {
+                fs::remove_file(log_path(&self.path, stale_gen))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish_compaction(true) {
+            error!("Compaction failed: {}", err);
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set_with_ttl(&self, key: String, value: String, ttl: Option<Duration>) -> Result<()> {
+        let ticket = self.writer.lock().unwrap().set(key, value, ttl)?;
+        self.syncer.commit(ticket)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match lookup(&self.entries, &key) {
+            Some(cmd_pos) => self.read_key(&key, cmd_pos),
+            None => Ok(None),
+        }
+    }
+
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        match lookup(&self.entries, &key) {
+            Some(cmd_pos) => Ok(cmd_pos.expires_at.map(expiry::remaining)),
+            None => Err(KvError::KeyNotFound),
+        }
+    }
+
+    fn scan(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        if is_empty_range(&start, &end) {
+            return Ok(Vec::new());
+        }
+        let found: Vec<(String, CommandPos)> = self
+            .entries
+            .range((start, end))
+            .map(|entry| (entry.key().to_owned(), entry.value().load()))
+            .filter(|(_, cmd_pos)| !cmd_pos.is_expired())
+            .take(limit.unwrap_or(usize::MAX))
+            .collect();
+
+        let mut pairs = Vec::with_capacity(found.len());
+        for (key, cmd_pos) in found {
+            // A key removed since it was found is left out.
+            if let Some(value) = self.read_key(&key, cmd_pos)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let ticket = self.writer.lock().unwrap().remove(key)?;
+        self.syncer.commit(ticket)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        // Every write goes through the writer, so holding it keeps the key
+        // from changing between the read and the write below.
+        let mut writer = self.writer.lock().unwrap();
+        if self.get(key.clone())? != expected {
+            return Ok(false);
+        }
+        let ticket = match new {
+            Some(value) => writer.set(key, value, None)?,
+            None if expected.is_some() => writer.remove(key)?,
+            None => return Ok(true),
+        };
+        drop(writer);
+        self.syncer.commit(ticket)?;
+        Ok(true)
+    }
+
+    fn write_batch(&self, ops: Vec<Op>) -> Result<()> {
+        match self.writer.lock().unwrap().write_batch(ops)? {
+            Some(ticket) => self.syncer.commit(ticket),
+            None => Ok(()),
+        }
+    }
+
+    fn open(path: &Path) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+}
+
+/// Copies the values in `snapshot` into generation `gen`.
+///
+/// The entries go to a temporary file first which is synced and then renamed
+/// to `<gen>.log`, so a crash at any point leaves either the old generations
+/// or a complete compacted one behind.
+fn compact(path: &Path, gen: u64, snapshot: Vec<(String, CommandPos)>) -> Result<Compacted> {
+    let compact_path = path.join(format!("{}.compact", gen));
+    let mut writer = BufWriterWithPos::new(
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&compact_path)?,
+    )?;
+
+    let mut readers = HashMap::new();
+    let mut moved = Vec::with_capacity(snapshot.len());
+    for (key, old_pos) in snapshot {
+        let reader = match readers.entry(old_pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(BufReaderWithPos::new(File::open(log_path(path, old_pos.gen))?)?)
+            }
+        };
+        reader.seek(SeekFrom::Start(old_pos.pos))?;
+
+        let pos = writer.pos;
+        io::copy(&mut reader.take(old_pos.len), &mut writer)?;
+        moved.push((key, old_pos, CommandPos { gen, pos, ..old_pos }));
+    }
+    writer.flush()?;
+    writer.writer.get_ref().sync_all()?;
+
+    fs::rename(&compact_path, log_path(path, gen))?;
+    Ok(Compacted { gen, moved })
+}
+
+/// Replays one generation of the log into the index.
+///
+/// Returns the offset where the intact records end. Anything past that
+/// offset is a record that was only partly written; a damaged record with
+/// more data behind it is an error.
+fn load(gen: u64, reader: &mut BufReaderWithPos<File>, index: &mut Index) -> Result<u64> {
+    let file_len = reader.reader.get_ref().metadata()?.len();
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    while let Some((record, len)) = next_record(gen, reader, file_len, pos)? {
+        let count = match record {
+            Record::Batch(count) => count,
+            record => {
+                replay(gen, index, record, pos, len)?;
+                pos += len;
+                continue;
+            }
+        };
+
+        let mut batch = Vec::with_capacity(count as usize);
+        let mut op_pos = pos + len;
+        for _ in 0..count {
+            match next_record(gen, reader, file_len, op_pos)? {
+                Some((record, len)) => {
+                    batch.push((record, op_pos, len));
+                    op_pos += len;
+                }
+                // The batch never made it to disk in full, so none of it
+                // happened.
+                None => return Ok(pos),
+            }
+        }
+        index.uncompacted += len;
+        for (record, op_pos, len) in batch {
+            replay(gen, index, record, op_pos, len)?;
+        }
+        pos = op_pos;
+    }
+    Ok(pos)
+}
+
+/// Reads the record at `pos`, or `None` where the intact records end.
+fn next_record(
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    file_len: u64,
+    pos: u64,
+) -> Result<Option<(Record, u64)>> {
+    match Record::read_from(reader)? {
+        ReadResult::Record(record, len) => Ok(Some((record, len))),
+        ReadResult::Eof | ReadResult::Torn => Ok(None),
+        // A torn write can leave garbage in the last record as well, or
+        // leave the file extended with zeros the data never reached.
+        ReadResult::Corrupted if reader.pos == file_len || only_zeros(reader, pos)? => Ok(None),
+        ReadResult::Corrupted => Err(KvError::Corrupted { gen, offset: pos }),
+    }
+}
+
+/// Whether everything from `pos` to the end of the file is zero.
+fn only_zeros(reader: &mut BufReaderWithPos<File>, pos: u64) -> Result<bool> {
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut buf = [0; 4096];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(true),
+            n if buf[..n].iter().any(|&byte| byte != 0) => return Ok(false),
+            _ => (),
+        }
+    }
+}
+
+/// Applies a single replayed record to the index.
+fn replay(gen: u64, index: &mut Index, record: Record, pos: u64, len: u64) -> Result<()> {
+    match record {
+        Record::Set(key, _, expires_at) => {
+            let cmd_pos = CommandPos {
+                gen,
+                pos,
+                len,
+                expires_at,
+            };
+            // An expired entry still hides whatever came before it.
+            if cmd_pos.is_expired() {
+                index.remove(&key);
+                index.uncompacted += len;
+            } else {
+                index.insert(key, cmd_pos);
+            }
+        }
+        Record::Remove(key) => {
+            index.remove(&key);
+            index.uncompacted += len;
+        }
+        // Batches don't nest.
+        Record::Batch(_) => return Err(KvError::Corrupted { gen, offset: pos }),
+    }
+    Ok(())
+}
+
+pub(super) fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+/// Returns the generations found in `path`, oldest first.
+pub(super) fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        if entry_path.is_file() && entry_path.extension() == Some(OsStr::new("log")) {
+            if let Some(gen) = entry_path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                gen_list.push(gen);
+            }
+        }
+    }
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// Creates the log file for `gen` and returns a writer appending to it.
+fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
+    let path = log_path(path, gen);
+    let writer = BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(&path)?)?;
+    Ok(writer)
+}
+
+struct BufReaderWithPos<R: Read + Seek> {
+    reader: BufReader<R>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    fn new(mut inner: R) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufReaderWithPos {
+            reader: BufReader::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for BufReaderWithPos<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+struct BufWriterWithPos<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pos: u64,
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    fn new(mut inner: W) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::End(0))?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
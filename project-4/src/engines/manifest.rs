@@ -0,0 +1,96 @@
+//! The `MANIFEST` file that says which engine owns a data directory.
+//!
+//! It's a small JSON document written next to the engine's own files:
+//!
+//! ```text
+//! {"engine":"kvs","format_version":5,"parameters":{"compaction_threshold":"1048576"}}
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvError, Result};
+
+const FILENAME: &str = "MANIFEST";
+const TMP_FILENAME: &str = "MANIFEST.tmp";
+
+/// Name of the single file sled 0.29 keeps its data in.
+const SLED_FILENAME: &str = "db";
+const SLED_MAGIC: &[u8] = &[255, 186, 199, 15, 255, 255, 255, 255, 255, 255];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(super) struct Manifest {
+    pub engine: String,
+    pub format_version: u32,
+    /// The options the directory was created with, for reference.
+    pub parameters: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    pub fn new(engine: &str, format_version: u32, parameters: BTreeMap<String, String>) -> Self {
+        Manifest {
+            engine: engine.to_owned(),
+            format_version,
+            parameters,
+        }
+    }
+
+    /// Reads the manifest in `dir`, if there is one.
+    pub fn load(dir: &Path) -> Result<Option<Manifest>> {
+        match File::open(dir.join(FILENAME)) {
+            Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes the manifest to `dir`, replacing any previous one atomically.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(TMP_FILENAME);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, dir.join(FILENAME))?;
+        Ok(())
+    }
+
+    /// Fails unless the directory belongs to `engine`.
+    pub fn check_engine(&self, engine: &str) -> Result<()> {
+        if self.engine != engine {
+            return Err(KvError::WrongEngine {
+                expected: engine.to_owned(),
+                found: self.engine.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Guesses which engine wrote a directory from before manifests existed.
+///
+/// This is the only place that still looks at file contents to tell the
+/// engines apart; once a directory has a manifest it is never consulted.
+pub(super) fn legacy_engine(dir: &Path, has_kvs_logs: bool) -> Result<Option<&'static str>> {
+    let sled_path = dir.join(SLED_FILENAME);
+    if sled_path.exists() {
+        let mut first_bytes = Vec::new();
+        File::open(sled_path)?
+            .take(SLED_MAGIC.len() as u64)
+            .read_to_end(&mut first_bytes)?;
+        // Before generations, kvs kept its JSON log in a file of the same name.
+        return Ok(Some(if first_bytes == SLED_MAGIC { "sled" } else { "kvs" }));
+    }
+    if has_kvs_logs {
+        return Ok(Some("kvs"));
+    }
+    Ok(None)
+}
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+use super::expiry::{self, SWEEP_INTERVAL};
+use super::is_empty_range;
+use super::periodic::Periodic;
+use crate::{KvError, KvsEngine, Op, Result};
+
+type Entries = BTreeMap<String, Entry>;
+
+struct Entry {
+    value: String,
+    // Expiry time, see `expiry`.
+    deadline: Option<u64>,
+}
+
+impl Entry {
+    fn is_live(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => !expiry::is_expired(deadline),
+            None => true,
+        }
+    }
+}
+
+/// A `KvsEngine` that keeps everything in memory, for tests and caches
+/// that need not outlive the process.
+///
+/// Nothing is ever written to disk: every write is lost once the last
+/// clone is dropped. Clones share the same keys.
+#[derive(Clone)]
+pub struct MemKvsEngine {
+    entries: Arc<RwLock<Entries>>,
+    // Drops expired keys until the last clone goes away.
+    _sweeper: Arc<Periodic>,
+}
+
+impl MemKvsEngine {
+    /// Creates an empty engine.
+    pub fn new() -> MemKvsEngine {
+        let entries = Arc::new(RwLock::new(Entries::new()));
+        let sweeper = {
+            let entries = Arc::clone(&entries);
+            Periodic::start(SWEEP_INTERVAL, move || {
+                write(&entries).retain(|_, entry| entry.is_live())
+            })
+        };
+        MemKvsEngine {
+            entries,
+            _sweeper: Arc::new(sweeper),
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Entries> {
+        // A panic while holding the lock leaves no write half done.
+        self.entries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Entries> {
+        write(&self.entries)
+    }
+}
+
+impl Default for MemKvsEngine {
+    fn default() -> MemKvsEngine {
+        MemKvsEngine::new()
+    }
+}
+
+impl KvsEngine for MemKvsEngine {
+    fn set_with_ttl(&self, key: String, value: String, ttl: Option<Duration>) -> Result<()> {
+        let deadline = ttl.map(expiry::deadline);
+        self.write().insert(key, Entry { value, deadline });
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.read().get(&key) {
+            Some(entry) if entry.is_live() => Ok(Some(entry.value.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        match self.read().get(&key) {
+            Some(entry) if entry.is_live() => Ok(entry.deadline.map(expiry::remaining)),
+            _ => Err(KvError::KeyNotFound),
+        }
+    }
+
+    fn scan(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        if is_empty_range(&start, &end) {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .read()
+            .range((start, end))
+            .filter(|(_, entry)| entry.is_live())
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(key, entry)| (key.clone(), entry.value.clone()))
+            .collect())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self.write().remove(&key) {
+            Some(entry) if entry.is_live() => Ok(()),
+            _ => Err(KvError::KeyNotFound),
+        }
+    }
+
+    fn write_batch(&self, ops: Vec<Op>) -> Result<()> {
+        let mut entries = self.write();
+        // Checked against the keys as the batch leaves them before any
+        // write, so a failing batch changes nothing.
+        let mut live = BTreeMap::new();
+        for op in &ops {
+            match op {
+                Op::Set(key, _, _) => {
+                    live.insert(key.as_str(), true);
+                }
+                Op::Remove(key) => {
+                    let exists = match live.get(key.as_str()) {
+                        Some(exists) => *exists,
+                        None => entries.get(key).is_some_and(Entry::is_live),
+                    };
+                    if !exists {
+                        return Err(KvError::KeyNotFound);
+                    }
+                    live.insert(key.as_str(), false);
+                }
+            }
+        }
+        for op in ops {
+            match op {
+                Op::Set(key, value, ttl) => {
+                    let deadline = ttl.map(expiry::deadline);
+                    entries.insert(key, Entry { value, deadline });
+                }
+                Op::Remove(key) => {
+                    entries.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let mut entries = self.write();
+        let current = match entries.get(&key) {
+            Some(entry) if entry.is_live() => Some(&entry.value),
+            _ => None,
+        };
+        if current != expected.as_ref() {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => {
+                let deadline = None;
+                entries.insert(key, Entry { value, deadline });
+            }
+            None => {
+                entries.remove(&key);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns a new, empty engine: there is nothing at `path` to open.
+    fn open(_path: &Path) -> Result<MemKvsEngine> {
+        Ok(MemKvsEngine::new())
+    }
+}
+
+fn write(entries: &RwLock<Entries>) -> RwLockWriteGuard<'_, Entries> {
+    entries
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,132 @@
+//! Upgrades for data directories written by older versions of `KvStore`.
+//!
+//! Format versions:
+//!
+//! 1. A single newline-delimited JSON log named `db`, next to a `db-count`
+//!    file holding the number of writes since the last compaction.
+//! 2. The JSON log split into numbered `<gen>.log` generations.
+//! 3. Checksummed binary records, see `record`.
+//! 4. Set records that carry an expiry time.
+//! 5. Batch records.
+//!
+//! Every step brings a directory from one version to the next and can be
+//! re-run safely if it was interrupted, since the manifest is only bumped
+//! after a step has finished.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::Deserialize;
+
+use super::kvs::{log_path, sorted_gen_list};
+use super::manifest::Manifest;
+use super::record::{ReadResult, Record};
+use crate::Result;
+
+pub(super) const FORMAT_VERSION: u32 = 5;
+
+const LEGACY_FILENAME: &str = "db";
+const LEGACY_COUNT_FILENAME: &str = "db-count";
+
+/// `STEPS[n]` upgrades a directory from version `n + 1` to `n + 2`.
+const STEPS: &[fn(&Path) -> Result<()>] =
+    &[split_into_generations, convert_to_binary, new_record_type, new_record_type];
+
+/// How commands were spelled in the JSON logs of versions 1 and 2.
+#[derive(Deserialize)]
+enum LegacyCommand {
+    Set(String, String),
+    Remove(String),
+}
+
+/// Runs every step between the manifest's version and `FORMAT_VERSION`,
+/// saving the manifest after each one.
+pub(super) fn migrate(dir: &Path, manifest: &mut Manifest) -> Result<()> {
+    while manifest.format_version < FORMAT_VERSION {
+        STEPS[manifest.format_version as usize - 1](dir)?;
+        manifest.format_version += 1;
+        manifest.save(dir)?;
+    }
+    Ok(())
+}
+
+/// Works out the version of a kvs directory that has no manifest yet.
+pub(super) fn legacy_version(dir: &Path) -> Result<u32> {
+    if dir.join(LEGACY_FILENAME).exists() {
+        return Ok(1);
+    }
+    for gen in sorted_gen_list(dir)? {
+        if is_json_log(&log_path(dir, gen))? {
+            return Ok(2);
+        }
+    }
+    Ok(FORMAT_VERSION)
+}
+
+/// 1 -> 2: the `db` log becomes the newest generation and the write counter
+/// goes away.
+fn split_into_generations(dir: &Path) -> Result<()> {
+    let legacy_path = dir.join(LEGACY_FILENAME);
+    if legacy_path.exists() {
+        let gen = sorted_gen_list(dir)?.last().unwrap_or(&0) + 1;
+        fs::rename(legacy_path, log_path(dir, gen))?;
+    }
+
+    let count_path = dir.join(LEGACY_COUNT_FILENAME);
+    if count_path.exists() {
+        fs::remove_file(count_path)?;
+    }
+    Ok(())
+}
+
+/// 2 -> 3: every JSON generation is rewritten as binary records.
+///
+/// Lines that don't parse are dropped, which is what version 2 did with
+/// them when it replayed the log.
+fn convert_to_binary(dir: &Path) -> Result<()> {
+    for gen in sorted_gen_list(dir)? {
+        let path = log_path(dir, gen);
+        if !is_json_log(&path)? {
+            continue;
+        }
+
+        let tmp_path = dir.join(format!("{}.migrate", gen));
+        let mut writer = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&tmp_path)?,
+        );
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let record = match serde_json::from_str(&line?) {
+                Ok(LegacyCommand::Set(key, value)) => Record::Set(key, value, None),
+                Ok(LegacyCommand::Remove(key)) => Record::Remove(key),
+                Err(_) => continue,
+            };
+            record.write_to(&mut writer)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp_path, path)?;
+    }
+    Ok(())
+}
+
+/// 3 -> 4 and 4 -> 5: nothing to rewrite, each version only added a record
+/// type. The bump keeps older versions, which would take the new records
+/// for damage, away from the directory.
+fn new_record_type(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Tells a version 2 log apart from a version 3 one: binary logs start with
+/// an intact record, JSON logs don't.
+fn is_json_log(path: &Path) -> Result<bool> {
+    let mut file = File::open(path)?;
+    Ok(match Record::read_from(&mut file)? {
+        ReadResult::Record(..) | ReadResult::Eof => false,
+        ReadResult::Torn | ReadResult::Corrupted => true,
+    })
+}
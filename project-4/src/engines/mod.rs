@@ -0,0 +1,31 @@
+use std::ops::Bound;
+
+mod async_engine;
+mod durability;
+mod expiry;
+mod kvs;
+mod manifest;
+mod memory;
+mod migration;
+mod periodic;
+mod record;
+mod sled;
+
+pub use self::async_engine::AsyncKvsEngine;
+pub use self::durability::Durability;
+pub use self::kvs::{KvStore, KvStoreOptions};
+pub use self::memory::MemKvsEngine;
+pub use self::sled::SledKvsEngine;
+
+/// Whether no key can fall between `start` and `end`. Not every ordered
+/// map takes such ranges gracefully, so they're answered before getting
+/// there.
+fn is_empty_range(start: &Bound<String>, end: &Bound<String>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
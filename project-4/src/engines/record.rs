@@ -0,0 +1,156 @@
+//! On-disk layout of a single `KvStore` log record.
+//!
+//! Every record is a fixed header followed by its body:
+//!
+//! ```text
+//! | length: u32 | type: u8 | header crc32: u32 | body crc32: u32 | body: `length` bytes |
+//! ```
+//!
+//! Integers are little endian. The header checksum covers the length and
+//! the type, so a damaged length is caught before it is trusted to find the
+//! end of the record. Bodies are bincode encoded.
+//!
+//! A batch is a `Batch` record holding the number of records that follow
+//! it. Only a batch whose records all made it to disk counts.
+
+use std::io::{self, Read, Write};
+
+use crc32fast::Hasher;
+
+use crate::Result;
+
+pub(super) const HEADER_LEN: u64 = 13;
+
+const RECORD_SET: u8 = 1;
+const RECORD_REMOVE: u8 = 2;
+const RECORD_SET_EXPIRING: u8 = 3;
+const RECORD_BATCH: u8 = 4;
+
+/// A mutation as it is stored in the log.
+#[derive(Debug)]
+pub(super) enum Record {
+    /// A key, its value and when it expires, see `expiry`.
+    Set(String, String, Option<u64>),
+    Remove(String),
+    /// The start of a batch of this many records.
+    Batch(u32),
+}
+
+/// What came out of reading at some offset of a log file.
+pub(super) enum ReadResult {
+    /// A complete, intact record of the given length, header included.
+    Record(Record, u64),
+    /// The file ends exactly here.
+    Eof,
+    /// The file ends partway through a record whose header is intact, or
+    /// partway through the header itself.
+    Torn,
+    /// A header, or a complete record, whose checksum or type doesn't
+    /// check out.
+    Corrupted,
+}
+
+impl Record {
+    /// Writes the record and returns its length, header included.
+    pub(super) fn write_to<W: Write>(&self, writer: &mut W) -> Result<u64> {
+        let (record_type, body) = match self {
+            Record::Set(key, value, None) => (RECORD_SET, bincode::serialize(&(key, value))?),
+            Record::Set(key, value, Some(expires_at)) => (
+                RECORD_SET_EXPIRING,
+                bincode::serialize(&(key, value, expires_at))?,
+            ),
+            Record::Remove(key) => (RECORD_REMOVE, bincode::serialize(key)?),
+            Record::Batch(count) => (RECORD_BATCH, bincode::serialize(count)?),
+        };
+
+        let mut header = [0; HEADER_LEN as usize];
+        header[0..4].copy_from_slice(&(body.len() as u32).to_le_bytes());
+        header[4] = record_type;
+        let header_crc = crc32(&header[0..5]);
+        header[5..9].copy_from_slice(&header_crc.to_le_bytes());
+        header[9..13].copy_from_slice(&crc32(&body).to_le_bytes());
+
+        writer.write_all(&header)?;
+        writer.write_all(&body)?;
+        Ok(HEADER_LEN + body.len() as u64)
+    }
+
+    /// Reads the record starting at the current position of `reader`.
+    pub(super) fn read_from<R: Read>(reader: &mut R) -> Result<ReadResult> {
+        let mut header = [0; HEADER_LEN as usize];
+        match read_full(reader, &mut header)? {
+            0 => return Ok(ReadResult::Eof),
+            n if n < header.len() => return Ok(ReadResult::Torn),
+            _ => (),
+        }
+        if crc32(&header[0..5]) != to_u32(&header[5..9]) {
+            return Ok(ReadResult::Corrupted);
+        }
+        let body_len = to_u32(&header[0..4]);
+        let record_type = header[4];
+
+        let body = match read_body(reader, body_len)? {
+            Some(body) => body,
+            None => return Ok(ReadResult::Torn),
+        };
+        if crc32(&body) != to_u32(&header[9..13]) {
+            return Ok(ReadResult::Corrupted);
+        }
+        decode(record_type, &body)
+    }
+}
+
+/// Reads a body of `len` bytes, or `None` if the input ends first.
+fn read_body<R: Read>(reader: &mut R, len: u32) -> Result<Option<Vec<u8>>> {
+    // Read through `take` so a garbage length can't make us allocate
+    // more than the file actually holds.
+    let mut body = Vec::new();
+    if reader.by_ref().take(u64::from(len)).read_to_end(&mut body)? < len as usize {
+        return Ok(None);
+    }
+    Ok(Some(body))
+}
+
+/// Turns an intact body into its record.
+fn decode(record_type: u8, body: &[u8]) -> Result<ReadResult> {
+    let record = match record_type {
+        RECORD_SET => {
+            let (key, value) = bincode::deserialize(body)?;
+            Record::Set(key, value, None)
+        }
+        RECORD_SET_EXPIRING => {
+            let (key, value, expires_at) = bincode::deserialize(body)?;
+            Record::Set(key, value, Some(expires_at))
+        }
+        RECORD_REMOVE => Record::Remove(bincode::deserialize(body)?),
+        RECORD_BATCH => Record::Batch(bincode::deserialize(body)?),
+        _ => return Ok(ReadResult::Corrupted),
+    };
+    Ok(ReadResult::Record(record, HEADER_LEN + body.len() as u64))
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+fn to_u32(bytes: &[u8]) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(bytes);
+    u32::from_le_bytes(word)
+}
+
+/// Like `read_exact`, but reports how much was read when the input runs out.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
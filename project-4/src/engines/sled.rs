@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Bound;
+use std::path::Path;
+use std::str::from_utf8;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::error;
+use sled::{abort, Db, TransactionError, Transactional, Tree};
+
+use super::durability::{Durability, Syncer};
+use super::expiry::{self, SWEEP_INTERVAL};
+use super::is_empty_range;
+use super::kvs::sorted_gen_list;
+use super::manifest::{legacy_engine, Manifest};
+use super::periodic::Periodic;
+use crate::{KvError, KvsEngine, Op, Result};
+
+const ENGINE: &str = "sled";
+/// sled keeps its own on-disk format, so this only changes if the way
+/// we store keys and values in it does.
+///
+/// 1. Keys and values in the default tree.
+/// 2. Expiry times, as big endian milliseconds since the Unix epoch, in
+///    the `expiry` tree under the same key.
+const FORMAT_VERSION: u32 = 2;
+const SLED_VERSION: &str = "0.29";
+const EXPIRY_TREE: &str = "expiry";
+
+/// A `KvsEngine` backed by sled.
+///
+/// sled handles concurrent access itself, so clones share the database
+/// directly.
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    storage: Db,
+    expiry: Tree,
+    shared: Arc<Shared>,
+}
+
+/// What the clones of an engine share besides the database.
+struct Shared {
+    storage: Db,
+    syncer: Syncer,
+    // Drops expired keys until the last clone goes away.
+    _sweeper: Periodic,
+}
+
+impl SledKvsEngine {
+    /// Opens the database at `path`, flushing writes as `durability` says.
+    pub fn open_with_durability(path: &Path, durability: Durability) -> Result<SledKvsEngine> {
+        fs::create_dir_all(path)?;
+
+        let manifest = match Manifest::load(path)? {
+            Some(manifest) => Some(manifest),
+            None => {
+                let has_logs = !sorted_gen_list(path)?.is_empty();
+                match legacy_engine(path, has_logs)? {
+                    Some(ENGINE) | None => None,
+                    Some(engine) => {
+                        return Err(KvError::WrongEngine {
+                            expected: ENGINE.to_owned(),
+                            found: engine.to_owned(),
+                        })
+                    }
+                }
+            }
+        };
+        if let Some(manifest) = &manifest {
+            manifest.check_engine(ENGINE)?;
+            if manifest.format_version == 0 || manifest.format_version > FORMAT_VERSION {
+                return Err(KvError::UnsupportedVersion {
+                    engine: ENGINE.to_owned(),
+                    version: manifest.format_version,
+                });
+            }
+        }
+
+        let storage = Db::open(path)?;
+        let expiry = storage.open_tree(EXPIRY_TREE)?;
+        match manifest {
+            // Version 1 had no expiring keys, so an empty `expiry` tree is
+            // all it takes to upgrade.
+            Some(mut manifest) => {
+                if manifest.format_version < FORMAT_VERSION {
+                    manifest.format_version = FORMAT_VERSION;
+                    manifest.save(path)?;
+                }
+            }
+            None => {
+                let mut parameters = BTreeMap::new();
+                parameters.insert("sled_version".to_owned(), SLED_VERSION.to_owned());
+                Manifest::new(ENGINE, FORMAT_VERSION, parameters).save(path)?;
+            }
+        }
+        let syncer = {
+            let storage = storage.clone();
+            Syncer::new(durability, move || {
+                storage.flush()?;
+                Ok(())
+            })
+        };
+        let sweeper = {
+            let storage = storage.clone();
+            let expiry = expiry.clone();
+            Periodic::start(SWEEP_INTERVAL, move || {
+                if let Err(err) = sweep(&storage, &expiry) {
+                    error!("Expiry sweep failed: {}", err);
+                }
+            })
+        };
+        Ok(SledKvsEngine {
+            storage: storage.clone(),
+            expiry,
+            shared: Arc::new(Shared {
+                storage,
+                syncer,
+                _sweeper: sweeper,
+            }),
+        })
+    }
+
+    fn is_expired(&self, key: &[u8]) -> Result<bool> {
+        match self.expiry.get(key)? {
+            Some(deadline) => Ok(expiry::is_expired(to_deadline(&deadline))),
+            None => Ok(false),
+        }
+    }
+
+    fn commit(&self) -> Result<()> {
+        let syncer = &self.shared.syncer;
+        let ticket = syncer.written();
+        syncer.commit(ticket)
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set_with_ttl(&self, key: String, value: String, ttl: Option<Duration>) -> Result<()> {
+        let deadline = ttl.map(|ttl| expiry::deadline(ttl).to_be_bytes());
+        (&*self.storage, &self.expiry).transaction(|(storage, expiry)| {
+            storage.insert(key.as_bytes(), value.as_bytes())?;
+            match deadline {
+                Some(deadline) => expiry.insert(key.as_bytes(), &deadline[..])?,
+                None => expiry.remove(key.as_bytes())?,
+            };
+            Ok(())
+        })?;
+        self.commit()
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if self.is_expired(key.as_bytes())? {
+            return Ok(None);
+        }
+        match self.storage.get(key.into_bytes())? {
+            Some(value) => Ok(Some(to_string(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        if self.is_expired(key.as_bytes())? || !self.storage.contains_key(key.as_bytes())? {
+            return Err(KvError::KeyNotFound);
+        }
+        match self.expiry.get(key.as_bytes())? {
+            Some(deadline) => Ok(Some(expiry::remaining(to_deadline(&deadline)))),
+            None => Ok(None),
+        }
+    }
+
+    fn scan(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        if is_empty_range(&start, &end) {
+            return Ok(Vec::new());
+        }
+        // UTF-8 sorts bytewise in the same order as the strings themselves.
+        let range = (map_bound(start), map_bound(end));
+        let mut pairs = Vec::new();
+        for pair in self.storage.range::<Vec<u8>, _>(range) {
+            if pairs.len() == limit.unwrap_or(usize::MAX) {
+                break;
+            }
+            let (key, value) = pair?;
+            if !self.is_expired(&key)? {
+                pairs.push((to_string(&key)?, to_string(&value)?));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let expired = self.is_expired(key.as_bytes())?;
+        let removed = (&*self.storage, &self.expiry).transaction(|(storage, expiry)| {
+            expiry.remove(key.as_bytes())?;
+            Ok(storage.remove(key.as_bytes())?)
+        })?;
+        match removed {
+            Some(_) if !expired => self.commit(),
+            _ => Err(KvError::KeyNotFound),
+        }
+    }
+
+    fn write_batch(&self, ops: Vec<Op>) -> Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        // Worked out once, the transaction below may be retried.
+        let deadlines: Vec<Option<[u8; 8]>> = ops
+            .iter()
+            .map(|op| match op {
+                Op::Set(_, _, Some(ttl)) => Some(expiry::deadline(*ttl).to_be_bytes()),
+                _ => None,
+            })
+            .collect();
+
+        let result = (&*self.storage, &self.expiry).transaction(|(storage, expiry)| {
+            for (op, deadline) in ops.iter().zip(&deadlines) {
+                match op {
+                    Op::Set(key, value, _) => {
+                        storage.insert(key.as_bytes(), value.as_bytes())?;
+                        match deadline {
+                            Some(deadline) => expiry.insert(key.as_bytes(), &deadline[..])?,
+                            None => expiry.remove(key.as_bytes())?,
+                        };
+                    }
+                    Op::Remove(key) => {
+                        let expired = match expiry.remove(key.as_bytes())? {
+                            Some(deadline) => expiry::is_expired(to_deadline(&deadline)),
+                            None => false,
+                        };
+                        if storage.remove(key.as_bytes())?.is_none() || expired {
+                            return abort(());
+                        }
+                    }
+                }
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => self.commit(),
+            Err(TransactionError::Abort(())) => Err(KvError::KeyNotFound),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let swapped = (&*self.storage, &self.expiry).transaction(|(storage, expiry)| {
+            let expired = match expiry.get(key.as_bytes())? {
+                Some(deadline) => expiry::is_expired(to_deadline(&deadline)),
+                None => false,
+            };
+            let current = match storage.get(key.as_bytes())? {
+                Some(_) if expired => None,
+                current => current,
+            };
+            let expected = expected.as_ref().map(String::as_bytes);
+            if current.as_ref().map(|value| value.as_ref()) != expected {
+                return Ok(false);
+            }
+            match &new {
+                Some(value) => {
+                    storage.insert(key.as_bytes(), value.as_bytes())?;
+                }
+                None => {
+                    storage.remove(key.as_bytes())?;
+                }
+            }
+            expiry.remove(key.as_bytes())?;
+            Ok(true)
+        })?;
+        if swapped {
+            self.commit()?;
+        }
+        Ok(swapped)
+    }
+
+    /// Flushes every write before it returns, like the engine always has.
+    fn open(path: &Path) -> Result<SledKvsEngine> {
+        SledKvsEngine::open_with_durability(path, Durability::EveryWrite)
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // sled only flushes on its own once nothing refers to the database
+        // any more, which other threads may keep from happening for a
+        // while. Whatever the durability, a closed engine is on disk.
+        if let Err(err) = self.storage.flush() {
+            error!("Final flush failed: {}", err);
+        }
+    }
+}
+
+/// Removes every key whose expiry time has passed.
+fn sweep(storage: &Db, expiry: &Tree) -> Result<()> {
+    for entry in expiry.iter() {
+        let (key, deadline) = entry?;
+        if !expiry::is_expired(to_deadline(&deadline)) {
+            continue;
+        }
+        // The key may have been set again since it was read above.
+        (&**storage, expiry).transaction(|(storage, expiry)| {
+            if expiry.get(&key)?.as_ref() == Some(&deadline) {
+                expiry.remove(&key)?;
+                storage.remove(&key)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+fn to_deadline(bytes: &[u8]) -> u64 {
+    let mut deadline = [0; 8];
+    deadline.copy_from_slice(bytes);
+    u64::from_be_bytes(deadline)
+}
+
+fn to_string(bytes: &[u8]) -> Result<String> {
+    match from_utf8(bytes) {
+        Ok(value) => Ok(value.to_owned()),
+        Err(err) => Err(KvError::SerdeError(err.to_string())),
+    }
+}
+
+fn map_bound(bound: Bound<String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.into_bytes()),
+        Bound::Excluded(key) => Bound::Excluded(key.into_bytes()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
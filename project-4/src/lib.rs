@@ -1,32 +1,71 @@
-use std::collections::HashMap;
 use std::fmt;
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Write, Read};
+use std::io;
+use std::ops::Bound;
 use std::path::Path;
 use std::result;
-use sled::Db;
-
+use std::time::Duration;
 
+use futures::Future;
 use serde::{Deserialize, Serialize};
-use failure::_core::str::from_utf8;
 
+mod async_client;
+mod async_server;
+mod client;
+mod engines;
+pub mod protocol;
+pub mod resp;
+mod server;
 pub mod thread_pool;
 
-const FILENAME: &str = "db";
-const COMPACT_LIMIT: i32 = 1_000;
+pub use async_client::{AsyncKvsClient, ClientReply};
+pub use async_server::{AsyncKvsServer, AsyncServerHandle};
+pub use client::{KvsClient, KvsClientOptions};
+pub use engines::{
+    AsyncKvsEngine, Durability, KvStore, KvStoreOptions, MemKvsEngine, SledKvsEngine,
+};
+pub use server::{KvsServer, Protocol, ServerHandle};
 
 #[derive(Serialize, Deserialize)]
 pub enum KvError {
     KeyNotFound,
     IoError(String),
     SerdeError(String),
+    /// A log record that failed its checksum, with more data after it.
+    Corrupted { gen: u64, offset: u64 },
+    /// The data directory belongs to another engine.
+    WrongEngine { expected: String, found: String },
+    /// The data directory is in a format this version can't read.
+    UnsupportedVersion { engine: String, version: u32 },
+    /// The other end of a connection broke the wire protocol.
+    ProtocolError(String),
+    /// The other end of a connection took too long to answer.
+    Timeout,
+    /// The server's job queue is full and it turned the request away.
+    Busy,
+}
+
+/// One mutation in a `KvsEngine::write_batch`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Op {
+    /// A key, its value and, optionally, how long until it expires.
+    Set(String, String, Option<Duration>),
+    Remove(String),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum KvsCommand {
-    Set(String, String),
+    /// A key, its value and, optionally, how long until it expires.
+    Set(String, String, Option<Duration>),
     Remove(String),
     Get(String),
+    Ttl(String),
+    /// Mutations that are applied all together or not at all.
+    Batch(Vec<Op>),
+    /// A key, the value it must have and the value to replace it with.
+    /// `None` stands for the key being absent.
+    CompareAndSwap(String, Option<String>, Option<String>),
+    /// Keys from the first bound to the second, at most as many as given.
+    Scan(Bound<String>, Bound<String>, Option<usize>),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -35,6 +74,11 @@ pub enum KvsResult {
     None,
     Error(KvError),
     Ok,
+    Pairs(Vec<(String, String)>),
+    /// Time left before a key expires, `None` if it never does.
+    Ttl(Option<Duration>),
+    /// Whether a compare-and-swap found the expected value.
+    Swapped(bool),
 }
 
 impl fmt::Debug for KvError {
@@ -43,6 +87,18 @@ impl fmt::Debug for KvError {
             KvError::KeyNotFound => write!(f, "Key not found"),
             KvError::IoError(err) => write!(f, "IO error: {}", err),
             KvError::SerdeError(err) => write!(f, "Serde error: {}", err),
+            KvError::Corrupted { gen, offset } => {
+                write!(f, "Corrupted log record in generation {} at offset {}", gen, offset)
+            }
+            KvError::WrongEngine { expected, found } => {
+                write!(f, "Wrong engine: expected {}, found {}", expected, found)
+            }
+            KvError::UnsupportedVersion { engine, version } => {
+                write!(f, "Unsupported {} format version {}", engine, version)
+            }
+            KvError::ProtocolError(err) => write!(f, "Protocol error: {}", err),
+            KvError::Timeout => write!(f, "Timed out"),
+            KvError::Busy => write!(f, "Server busy"),
         }
     }
 }
@@ -53,225 +109,123 @@ impl fmt::Display for KvError {
             KvError::KeyNotFound => write!(f, "Key not found"),
             KvError::IoError(err) => write!(f, "IO error: {}", err),
             KvError::SerdeError(err) => write!(f, "Serde error: {}", err),
-        }
-    }
-}
-
-pub type Result<T> = result::Result<T, KvError>;
-
-pub trait KvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    fn remove(&mut self, key: String) -> Result<()>;
-    fn open(path: &Path) -> Result<Self> where Self: Sized;
-}
-
-#[derive(Debug, Default)]
-pub struct KvStore {
-    storage: HashMap<String, String>,
-    path: String,
-}
-
-pub struct SledKvsEngine {
-    storage: Db,
-}
-
-impl KvStore {
-    fn new() -> Self {
-        let storage = HashMap::new();
-        KvStore {
-            storage,
-            path: FILENAME.to_string(),
-        }
-    }
-
-    fn load(store: &mut KvStore, command: &KvsCommand) -> Result<()> {
-        match command {
-            KvsCommand::Set(key, value) => {
-                store.storage.insert(key.to_owned(), value.to_owned());
+            KvError::Corrupted { gen, offset } => {
+                write!(f, "Corrupted log record in generation {} at offset {}", gen, offset)
             }
-            KvsCommand::Remove(key) => {
-                store.storage.remove(key);
+            KvError::WrongEngine { expected, found } => {
+                write!(f, "Wrong engine: expected {}, found {}", expected, found)
             }
-            _ => (),
-        }
-
-        Ok(())
-    }
-
-    fn save(store: &KvStore, command: &KvsCommand) -> Result<()> {
-        let path = Path::new(&store.path);
-        let path_count_str = format!("{}-count", &store.path);
-        let path_count = Path::new(&path_count_str);
-
-        let mut count = 0;
-        if let Ok(file) = OpenOptions::new().read(true).open(&path_count) {
-            if let Ok(KvsCommand::Set(key, value)) = serde_json::from_reader::<File, KvsCommand>(file) {
-                if key == "count" {
-                    count = value.parse().unwrap_or(0);
-                }
+            KvError::UnsupportedVersion { engine, version } => {
+                write!(f, "Unsupported {} format version {}", engine, version)
             }
+            KvError::ProtocolError(err) => write!(f, "Protocol error: {}", err),
+            KvError::Timeout => write!(f, "Timed out"),
+            KvError::Busy => write!(f, "Server busy"),
         }
-
-        // Do compaction
-        if count > COMPACT_LIMIT {
-            if let Ok(mut file) = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(path)
-            {
-                for (k, v) in &store.storage {
-                    KvStore::write_command(&mut file, &KvsCommand::Set(k.to_owned(), v.to_owned()))?;
-                }
-
-                // Reset count
-                if let Ok(mut file) = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .truncate(true)
-                    .open(path_count)
-                {
-                    KvStore::write_command(
-                        &mut file,
-                        &KvsCommand::Set("count".to_owned(), "0".to_owned()),
-                    )?;
-                }
-            }
-        } else if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
-            KvStore::write_command(&mut file, &command)?;
-
-            // Increment count
-            if let Ok(mut file) = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(path_count)
-            {
-                KvStore::write_command(
-                    &mut file,
-                    &KvsCommand::Set("count".to_owned(), (count + 1).to_string()),
-                )?;
-            }
-        }
-
-        Ok(())
     }
+}
 
-    fn write_command(file: &mut File, command: &KvsCommand) -> Result<()> {
-        let cmd = serde_json::to_string(command).unwrap();
-        match writeln!(file, "{}", cmd) {
-            Ok(_) => Ok(()),
-            Err(err) => Err(KvError::IoError(err.to_string())),
+impl From<io::Error> for KvError {
+    fn from(err: io::Error) -> KvError {
+        match err.kind() {
+            // Socket timeouts surface as either, depending on the platform.
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => KvError::Timeout,
+            _ => KvError::IoError(err.to_string()),
         }
     }
+}
 
-    fn clone(&self) -> Self {
-        return KvStore{ storage: self.storage.clone(), path: self.path.clone() }
+impl From<serde_json::Error> for KvError {
+    fn from(err: serde_json::Error) -> KvError {
+        KvError::SerdeError(err.to_string())
     }
 }
 
-impl KvsEngine for KvStore {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.storage.insert(key.to_owned(), value.to_owned());
-        KvStore::save(&self, &KvsCommand::Set(key, value))?;
-        Ok(())
-    }
-
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.storage.get(&key) {
-            Some(s) => Ok(Some(s.to_owned())),
-            None => Ok(None),
-        }
+impl From<bincode::Error> for KvError {
+    fn from(err: bincode::Error) -> KvError {
+        KvError::SerdeError(err.to_string())
     }
+}
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        match self.get(key.to_owned())? {
-            Some(_) => {
-                self.storage.remove(&key);
-                KvStore::save(&self, &KvsCommand::Remove(key))?;
-                Ok(())
-            }
-            None => Err(KvError::KeyNotFound),
-        }
+impl From<sled::Error> for KvError {
+    fn from(err: sled::Error) -> KvError {
+        KvError::IoError(err.to_string())
     }
+}
 
-    fn open(path: &Path) -> Result<KvStore> {
-        let full_path = path.join(FILENAME);
-        let mut store = KvStore::new();
-        store.path = full_path.to_str().unwrap().to_string();
-
-        if !full_path.exists() {
-            store.set("".to_owned(), "".to_owned());
-            return Ok(store);
-        } else {
-            let first_10_bytes_of_kvs = &[123, 34, 83, 101, 116, 34, 58, 91, 34, 34];
-            let mut file = OpenOptions::new().read(true).open(&full_path).unwrap();
-            let mut first_bytes = [0; 10];
-            file.read(&mut first_bytes);
-            if !first_bytes.starts_with(first_10_bytes_of_kvs) {
-                return Err(KvError::IoError("Unable to open!".to_owned()));
-            }
-        }
-
-        if let Ok(file) = OpenOptions::new().read(true).open(&full_path) {
-            let reader = BufReader::new(file);
-            for line in reader.lines() {
-                if let Ok(cmd) = line {
-                    if let Ok(cmd) = serde_json::from_str::<KvsCommand>(&cmd) {
-                        KvStore::load(&mut store, &cmd);
-                    };
-                }
-            }
-            return Ok(store);
+impl From<sled::TransactionError<()>> for KvError {
+    fn from(err: sled::TransactionError<()>) -> KvError {
+        match err {
+            sled::TransactionError::Abort(()) => KvError::IoError("Transaction aborted".to_owned()),
+            sled::TransactionError::Storage(err) => err.into(),
         }
-
-        return Err(KvError::IoError("Unable to open!".to_owned()));
     }
 }
 
-impl KvsEngine for SledKvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        &self.storage.insert(key.into_bytes(), value.into_bytes());
-        &self.storage.flush();
-        Ok(())
-    }
+pub type Result<T> = result::Result<T, KvError>;
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        match &self.storage.get(key.into_bytes()) {
-            Ok(Some(value)) => Ok(Some(from_utf8(value.as_ref()).unwrap().to_string())),
-            Ok(None) => Ok(None),
-            Err(err) => Err(KvError::KeyNotFound),
-        }
-    }
+/// The result of an async call, resolved once the work behind it is done.
+pub type KvsFuture<T> = Box<dyn Future<Item = T, Error = KvError> + Send>;
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        match &self.storage.get(key.as_bytes()) {
-            Ok(Some(_)) => {
-                &self.storage.remove(key.into_bytes());
-                &self.storage.flush();
-                Ok(())
-            }
-            _ => Err(KvError::KeyNotFound)
-        }
+/// A key/value store.
+///
+/// Engines are handles: clones share the same store, and each clone can
+/// be used from its own thread.
+pub trait KvsEngine: Clone + Send + 'static {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_with_ttl(key, value, None)
     }
+    /// Sets `key`, making it disappear once `ttl` has passed if one is
+    /// given. Setting a key again replaces its time-to-live as well.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Option<Duration>) -> Result<()>;
+    fn get(&self, key: String) -> Result<Option<String>>;
+    /// How long `key` has left before it expires, `None` if it never does.
+    fn ttl(&self, key: String) -> Result<Option<Duration>>;
+    fn remove(&self, key: String) -> Result<()>;
+    /// Applies `ops` in order as a single atomic write: after a crash
+    /// either all of them are there or none. Removing a key that doesn't
+    /// exist at that point of the batch fails the whole batch.
+    fn write_batch(&self, ops: Vec<Op>) -> Result<()>;
+    /// Replaces the value of `key` with `new` if it currently is
+    /// `expected`, where `None` means the key is absent on either side.
+    /// Returns whether the swap happened. Like `set`, a swap clears any
+    /// time-to-live the key had.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool>;
+    /// Returns the key/value pairs between `start` and `end` in key order,
+    /// stopping after `limit` of them.
+    fn scan(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>>;
+    /// Returns the key/value pairs whose key starts with `prefix`, in key
+    /// order.
+    fn scan_prefix(&self, prefix: String, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        let end = prefix_end(&prefix);
+        self.scan(Bound::Included(prefix), end, limit)
+    }
+    fn open(path: &Path) -> Result<Self> where Self: Sized;
+}
 
-    fn open(path: &Path) -> Result<SledKvsEngine> {
-        let full_path = path.join(FILENAME);
-        if !full_path.exists() {
-            let storage = Db::open(path).unwrap();
-            return Ok(SledKvsEngine { storage });
-        } else {
-            let first_10_bytes_of_sled = &[255, 186, 199, 15, 255, 255, 255, 255, 255, 255];
-            let mut file = OpenOptions::new().read(true).open(full_path).unwrap();
-            let mut first_bytes = [0; 10];
-            file.read(&mut first_bytes);
-            if !first_bytes.starts_with(first_10_bytes_of_sled) {
-                return Err(KvError::IoError("Unable to open!".to_owned()));
-            }
+/// The first string after every string starting with `prefix`.
+fn prefix_end(prefix: &str) -> Bound<String> {
+    let mut end: Vec<char> = prefix.chars().collect();
+    while let Some(last) = end.pop() {
+        // Skip over the surrogate range, which holds no chars.
+        let next = match last as u32 + 1 {
+            0xD800 => Some('\u{E000}'),
+            next => std::char::from_u32(next),
+        };
+        if let Some(next) = next {
+            end.push(next);
+            return Bound::Excluded(end.into_iter().collect());
         }
-
-        let storage = Db::open(path).unwrap();
-        return Ok(SledKvsEngine { storage });
     }
+    Bound::Unbounded
 }
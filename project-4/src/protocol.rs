@@ -0,0 +1,231 @@
+//! The framing spoken between `kvs-client` and `kvs-server`.
+//!
+//! Every message is a frame:
+//!
+//! ```text
+//! | version: u8 | length: u32 | body: `length` bytes |
+//! ```
+//!
+//! The length is little endian and the body is a bincode encoded `Request`
+//! or `Response`. A connection carries any number of frames in each
+//! direction; responses carry the id of the request they answer, so a
+//! client can send several requests before reading anything back.
+//!
+//! `FrameCodec` speaks the same framing on non-blocking streams, through
+//! `tokio::codec::Framed`.
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::marker::PhantomData;
+use std::net::{TcpStream, ToSocketAddrs};
+
+use bytes::BytesMut;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::codec::{Decoder, Encoder};
+
+use crate::{KvError, KvsCommand, KvsResult, Result};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Frames longer than this are refused rather than allocated.
+const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+/// The version byte and the length.
+const HEADER_LEN: usize = 5;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub id: u64,
+    pub command: KvsCommand,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    pub id: u64,
+    pub result: KvsResult,
+}
+
+/// Writes `message` as a single frame.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let body = bincode::serialize(message)?;
+    if body.len() > MAX_FRAME_LEN as usize {
+        return Err(too_long(body.len()));
+    }
+    writer.write_all(&[PROTOCOL_VERSION])?;
+    writer.write_all(&(body.len() as u32).to_le_bytes())?;
+    writer.write_all(&body)?;
+    Ok(())
+}
+
+/// Reads the next frame, or `None` if the other side closed the connection
+/// cleanly between frames.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut version = [0; 1];
+    match reader.read_exact(&mut version) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    check_version(version[0])?;
+
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(too_long(len as usize));
+    }
+    let mut body = vec![0; len as usize];
+    reader.read_exact(&mut body)?;
+    Ok(Some(bincode::deserialize(&body)?))
+}
+
+fn check_version(version: u8) -> Result<()> {
+    if version != PROTOCOL_VERSION {
+        return Err(KvError::ProtocolError(format!(
+            "unsupported protocol version {}",
+            version
+        )));
+    }
+    Ok(())
+}
+
+fn too_long(len: usize) -> KvError {
+    KvError::ProtocolError(format!("frame of {} bytes is too long", len))
+}
+
+/// Encodes `Out` messages into frames and decodes frames into `In`
+/// messages.
+pub struct FrameCodec<Out, In> {
+    _messages: PhantomData<fn(Out) -> In>,
+}
+
+/// The codec a client talks to `kvs-server` with.
+pub type ClientCodec = FrameCodec<Request, Response>;
+/// The codec `kvs-server` talks to its clients with.
+pub type ServerCodec = FrameCodec<Response, Request>;
+
+impl<Out, In> Default for FrameCodec<Out, In> {
+    fn default() -> Self {
+        FrameCodec {
+            _messages: PhantomData,
+        }
+    }
+}
+
+impl<Out: Serialize, In> Encoder for FrameCodec<Out, In> {
+    type Item = Out;
+    type Error = KvError;
+
+    fn encode(&mut self, message: Out, dst: &mut BytesMut) -> Result<()> {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, &message)?;
+        dst.extend_from_slice(&frame);
+        Ok(())
+    }
+}
+
+impl<Out, In: DeserializeOwned> Decoder for FrameCodec<Out, In> {
+    type Item = In;
+    type Error = KvError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<In>> {
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        check_version(src[0])?;
+        let mut len = [0; 4];
+        len.copy_from_slice(&src[1..HEADER_LEN]);
+        let len = u32::from_le_bytes(len);
+        if len > MAX_FRAME_LEN {
+            return Err(too_long(len as usize));
+        }
+
+        let frame_len = HEADER_LEN + len as usize;
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(frame_len);
+        Ok(Some(bincode::deserialize(&frame[HEADER_LEN..])?))
+    }
+}
+
+/// The client end of a persistent connection.
+///
+/// `send` only queues a request; `flush` puts everything queued on the
+/// wire, and `receive` reads responses back in the order they arrive.
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    next_id: u64,
+}
+
+impl Connection {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Connection> {
+        Connection::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// Wraps a stream that is already connected, keeping whatever timeouts
+    /// were set on it.
+    pub fn from_stream(stream: TcpStream) -> Result<Connection> {
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            next_id: 0,
+        })
+    }
+
+    /// Queues `command` and returns the id its response will carry.
+    pub fn send(&mut self, command: KvsCommand) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        write_frame(&mut self.writer, &Request { id, command })?;
+        Ok(id)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Waits for the next response.
+    pub fn receive(&mut self) -> Result<Response> {
+        match read_frame(&mut self.reader)? {
+            Some(response) => Ok(response),
+            None => Err(KvError::ProtocolError(
+                "connection closed by server".to_owned(),
+            )),
+        }
+    }
+
+    /// Whether the server has closed the connection, checked without
+    /// waiting.
+    pub fn is_closed(&self) -> bool {
+        if !self.reader.buffer().is_empty() {
+            return false;
+        }
+        let stream = self.reader.get_ref();
+        if stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let closed = match stream.peek(&mut [0]) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(err) => err.kind() != io::ErrorKind::WouldBlock,
+        };
+        stream.set_nonblocking(false).is_err() || closed
+    }
+
+    /// Sends a single command and waits for its result.
+    pub fn call(&mut self, command: KvsCommand) -> Result<KvsResult> {
+        let id = self.send(command)?;
+        self.flush()?;
+        let response = self.receive()?;
+        if response.id != id {
+            return Err(KvError::ProtocolError(format!(
+                "expected response {}, got {}",
+                id, response.id
+            )));
+        }
+        Ok(response.result)
+    }
+}
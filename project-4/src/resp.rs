@@ -0,0 +1,406 @@
+//! Redis serialization protocol (RESP2), so `kvs-server --protocol resp`
+//! can be used from `redis-cli` and Redis client libraries.
+//!
+//! Only the commands that map onto `KvsEngine` are understood: `PING`,
+//! `GET`, `SET` (with `EX` or `PX`), `DEL` and `EXISTS`.
+//!
+//! `RespCodec` reads and writes the same values on non-blocking streams,
+//! through `tokio::codec::Framed`.
+
+use std::io::{self, BufRead, Read, Write};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::codec::{Decoder, Encoder};
+
+use crate::{KvError, KvsEngine, Result};
+
+/// The longest bulk string or array accepted, the same as a frame of the
+/// kvs protocol. Redis itself goes up to 512 MiB.
+const MAX_LEN: i64 = 64 * 1024 * 1024;
+/// The longest inline command, or simple string or error, line ending
+/// included. Redis itself stops at 64 KiB as well.
+const MAX_INLINE_LEN: usize = 64 * 1024;
+/// The longest `*`, `$` or `:` header line, line ending included: enough
+/// for any 64-bit integer.
+const MAX_HEADER_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    SimpleString(String),
+    Error(String),
+    Integer(i64),
+    /// `None` is the null bulk string.
+    BulkString(Option<Vec<u8>>),
+    /// `None` is the null array.
+    Array(Option<Vec<Value>>),
+}
+
+impl Value {
+    fn ok() -> Value {
+        Value::SimpleString("OK".to_owned())
+    }
+
+    fn error(message: &str) -> Value {
+        Value::Error(format!("ERR {}", message))
+    }
+
+    fn bulk(value: String) -> Value {
+        Value::BulkString(Some(value.into_bytes()))
+    }
+}
+
+/// Reads the next value, or `None` if the connection was closed between
+/// values.
+///
+/// Besides RESP arrays this also takes inline commands, a plain line of
+/// space separated words, which is what `telnet` users type.
+///
+/// Arrays may only hold strings and integers, not other arrays: no
+/// request needs them, and reading them would take a stack frame per level
+/// of nesting a client asks for.
+pub fn read_value<R: BufRead>(reader: &mut R) -> Result<Option<Value>> {
+    read_nested(&mut Blocking(reader), false)
+}
+
+/// Where values are read from.
+trait Input: BufRead {
+    /// Called before reading `len` bytes that are certain to be needed;
+    /// `false` means they haven't arrived and reading should stop.
+    fn expect(&mut self, len: usize) -> bool;
+}
+
+/// A stream that waits for whatever it is asked for.
+struct Blocking<'a, R>(&'a mut R);
+
+impl<'a, R: BufRead> Read for Blocking<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<'a, R: BufRead> BufRead for Blocking<'a, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.0.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.0.consume(amt)
+    }
+}
+
+impl<'a, R: BufRead> Input for Blocking<'a, R> {
+    fn expect(&mut self, _len: usize) -> bool {
+        true
+    }
+}
+
+fn read_nested<R: Input>(reader: &mut R, in_array: bool) -> Result<Option<Value>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let (kind, rest) = match line.first() {
+        Some(&kind) => (kind, &line[1..]),
+        None => return Ok(Some(Value::Array(Some(Vec::new())))),
+    };
+
+    let value = match kind {
+        b'+' => Value::SimpleString(to_string(rest)?),
+        b'-' => Value::Error(to_string(rest)?),
+        b':' => Value::Integer(parse_int(rest)?),
+        b'$' => match parse_len(rest)? {
+            None => Value::BulkString(None),
+            Some(len) => {
+                if !reader.expect(len + 2) {
+                    return Err(protocol_error("bulk string not received yet"));
+                }
+                // Only what has arrived is allocated, not whatever length
+                // the client declares.
+                let mut body = Vec::new();
+                reader.by_ref().take(len as u64 + 2).read_to_end(&mut body)?;
+                if body.len() < len + 2 {
+                    return Err(protocol_error("connection closed inside a bulk string"));
+                }
+                if !body.ends_with(b"\r\n") {
+                    return Err(protocol_error("bulk string not terminated by CRLF"));
+                }
+                body.truncate(len);
+                Value::BulkString(Some(body))
+            }
+        },
+        b'*' if in_array => return Err(protocol_error("nested arrays are not supported")),
+        b'*' => match parse_len(rest)? {
+            None => Value::Array(None),
+            Some(len) => {
+                let mut items = Vec::with_capacity(len.min(1024));
+                for _ in 0..len {
+                    match read_nested(reader, true)? {
+                        Some(item) => items.push(item),
+                        None => return Err(protocol_error("connection closed inside an array")),
+                    }
+                }
+                Value::Array(Some(items))
+            }
+        },
+        _ => Value::Array(Some(
+            line.split(|&b| b == b' ')
+                .filter(|word| !word.is_empty())
+                .map(|word| Value::BulkString(Some(word.to_vec())))
+                .collect(),
+        )),
+    };
+    Ok(Some(value))
+}
+
+pub fn write_value<W: Write>(writer: &mut W, value: &Value) -> Result<()> {
+    match value {
+        Value::SimpleString(s) => write!(writer, "+{}\r\n", s)?,
+        Value::Error(s) => write!(writer, "-{}\r\n", s)?,
+        Value::Integer(n) => write!(writer, ":{}\r\n", n)?,
+        Value::BulkString(None) => writer.write_all(b"$-1\r\n")?,
+        Value::BulkString(Some(bytes)) => {
+            write!(writer, "${}\r\n", bytes.len())?;
+            writer.write_all(bytes)?;
+            writer.write_all(b"\r\n")?;
+        }
+        Value::Array(None) => writer.write_all(b"*-1\r\n")?,
+        Value::Array(Some(items)) => {
+            write!(writer, "*{}\r\n", items.len())?;
+            for item in items {
+                write_value(writer, item)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads and writes RESP values.
+#[derive(Default)]
+pub struct RespCodec {
+    /// How many bytes the value being received takes at least, so a long
+    /// bulk string is parsed and copied once, not on every read.
+    wanted: usize,
+}
+
+impl Encoder for RespCodec {
+    type Item = Value;
+    type Error = KvError;
+
+    fn encode(&mut self, value: Value, dst: &mut BytesMut) -> Result<()> {
+        let mut bytes = Vec::new();
+        write_value(&mut bytes, &value)?;
+        dst.extend_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Value;
+    type Error = KvError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Value>> {
+        if src.len() < self.wanted {
+            return Ok(None);
+        }
+        let mut input = Partial {
+            buf: &src[..],
+            pos: 0,
+            wanted: 0,
+            short: false,
+        };
+        let value = read_nested(&mut input, false);
+        // Whatever went wrong, it was for want of more input.
+        if input.short {
+            self.wanted = input.wanted;
+            return Ok(None);
+        }
+        self.wanted = 0;
+        let consumed = input.pos;
+        let value = value?;
+        src.advance(consumed);
+        Ok(value)
+    }
+}
+
+/// The input received so far, which remembers whether reading ran past
+/// the end of it.
+struct Partial<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    /// The length of input known to be needed.
+    wanted: usize,
+    short: bool,
+}
+
+impl<'a> Read for Partial<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (&self.fill_buf()?[..]).read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<'a> BufRead for Partial<'a> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            self.short = true;
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+impl<'a> Input for Partial<'a> {
+    fn expect(&mut self, len: usize) -> bool {
+        self.wanted = self.pos + len;
+        self.short = self.wanted > self.buf.len();
+        !self.short
+    }
+}
+
+/// Runs the command in `request` against `store` and returns the reply.
+pub fn execute<E: KvsEngine>(request: Value, store: &E) -> Value {
+    let args = match command_args(request) {
+        Ok(args) => args,
+        Err(reply) => return reply,
+    };
+    let name = match args.first() {
+        Some(name) => name.to_ascii_uppercase(),
+        None => return Value::error("empty command"),
+    };
+
+    let result = match (name.as_str(), &args[1..]) {
+        ("PING", []) => Ok(Value::SimpleString("PONG".to_owned())),
+        ("PING", [message]) => Ok(Value::bulk(message.clone())),
+        ("GET", [key]) => store.get(key.clone()).map(|value| match value {
+            Some(value) => Value::bulk(value),
+            None => Value::BulkString(None),
+        }),
+        ("SET", [key, value, options @ ..]) => match parse_ttl(options) {
+            Ok(ttl) => store
+                .set_with_ttl(key.clone(), value.clone(), ttl)
+                .map(|()| Value::ok()),
+            Err(reply) => Ok(reply),
+        },
+        ("DEL", keys) if !keys.is_empty() => {
+            let mut removed = 0;
+            for key in keys {
+                match store.remove(key.clone()) {
+                    Ok(()) => removed += 1,
+                    Err(KvError::KeyNotFound) => (),
+                    Err(err) => return Value::error(&err.to_string()),
+                }
+            }
+            Ok(Value::Integer(removed))
+        }
+        ("EXISTS", keys) if !keys.is_empty() => {
+            let mut found = 0;
+            for key in keys {
+                match store.get(key.clone()) {
+                    Ok(Some(_)) => found += 1,
+                    Ok(None) => (),
+                    Err(err) => return Value::error(&err.to_string()),
+                }
+            }
+            Ok(Value::Integer(found))
+        }
+        ("PING", _) | ("GET", _) | ("SET", _) | ("DEL", _) | ("EXISTS", _) => {
+            return Value::error(&format!(
+                "wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            ))
+        }
+        _ => return Value::error(&format!("unknown command '{}'", args[0])),
+    };
+    result.unwrap_or_else(|err| Value::error(&err.to_string()))
+}
+
+/// Turns a request, an array of bulk strings, into its words.
+fn command_args(request: Value) -> std::result::Result<Vec<String>, Value> {
+    let items = match request {
+        Value::Array(Some(items)) => items,
+        _ => return Err(Value::error("expected an array of bulk strings")),
+    };
+    let mut args = Vec::with_capacity(items.len());
+    for item in items {
+        match item {
+            Value::BulkString(Some(bytes)) => match String::from_utf8(bytes) {
+                Ok(arg) => args.push(arg),
+                Err(_) => return Err(Value::error("arguments must be valid UTF-8")),
+            },
+            _ => return Err(Value::error("expected an array of bulk strings")),
+        }
+    }
+    Ok(args)
+}
+
+/// Parses the `EX seconds` or `PX milliseconds` options of `SET`.
+fn parse_ttl(options: &[String]) -> std::result::Result<Option<Duration>, Value> {
+    match options {
+        [] => Ok(None),
+        [unit, amount] => {
+            let amount: u64 = match amount.parse() {
+                Ok(amount) if amount > 0 => amount,
+                _ => return Err(Value::error("invalid expire time in 'set' command")),
+            };
+            match unit.to_ascii_uppercase().as_str() {
+                "EX" => Ok(Some(Duration::from_secs(amount))),
+                "PX" => Ok(Some(Duration::from_millis(amount))),
+                _ => Err(Value::error("syntax error")),
+            }
+        }
+        _ => Err(Value::error("syntax error")),
+    }
+}
+
+/// Reads a line up to CRLF, without the line ending.
+///
+/// Lines are only read up to a limit that depends on their kind, so a
+/// client that never ends one can't make us buffer without bound.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let limit = match reader.fill_buf()?.first() {
+        None => return Ok(None),
+        Some(b'*') | Some(b'$') | Some(b':') => MAX_HEADER_LEN,
+        Some(_) => MAX_INLINE_LEN,
+    };
+    let mut line = Vec::new();
+    reader.by_ref().take(limit as u64).read_until(b'\n', &mut line)?;
+    if line.ends_with(b"\r\n") {
+        line.truncate(line.len() - 2);
+    } else if line.ends_with(b"\n") {
+        // Inline commands typed by hand may come without the CR.
+        line.truncate(line.len() - 1);
+    } else if line.len() == limit {
+        return Err(protocol_error("line too long"));
+    } else {
+        return Err(protocol_error("connection closed inside a line"));
+    }
+    Ok(Some(line))
+}
+
+fn parse_int(bytes: &[u8]) -> Result<i64> {
+    to_string(bytes)?
+        .parse()
+        .map_err(|_| protocol_error("invalid integer"))
+}
+
+/// Parses the length of a bulk string or array, where -1 means null.
+fn parse_len(bytes: &[u8]) -> Result<Option<usize>> {
+    match parse_int(bytes)? {
+        -1 => Ok(None),
+        len if (0..=MAX_LEN).contains(&len) => Ok(Some(len as usize)),
+        _ => Err(protocol_error("invalid length")),
+    }
+}
+
+fn to_string(bytes: &[u8]) -> Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| protocol_error("invalid UTF-8"))
+}
+
+fn protocol_error(message: &str) -> KvError {
+    KvError::ProtocolError(message.to_owned())
+}
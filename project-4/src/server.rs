@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, warn};
+
+use crate::protocol::{read_frame, write_frame, Request, Response};
+use crate::resp;
+use crate::thread_pool::ThreadPool;
+use crate::{KvError, KvsCommand, KvsEngine, KvsResult, Result};
+
+/// How long a connection may go without a request before it is closed.
+/// An open connection holds a thread of the pool, so one left idle would
+/// keep every client queued behind it waiting.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a busy reply waits for the client to hang up.
+const BUSY_LINGER: Duration = Duration::from_millis(100);
+/// How much of a turned away client's requests is read and dropped.
+const MAX_BUSY_DRAIN: u64 = 64 * 1024;
+/// How many busy replies may wait for their clients at once. Connections
+/// turned away past that are closed without one.
+const MAX_BUSY_PENDING: usize = 64;
+
+/// The wire protocol a `KvsServer` speaks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    /// The framed protocol in `kvs::protocol`, used by `KvsClient`.
+    Kvs,
+    /// RESP2, see `kvs::resp`.
+    Resp,
+}
+
+/// Serves a `KvsEngine` over TCP.
+///
+/// Every accepted connection is handed to the thread pool and served
+/// until the client hangs up or sends nothing for the idle timeout. If the
+/// pool rejects it, the client gets a single `KvError::Busy` reply and the
+/// connection is closed. Each connection works on its own clone of the
+/// engine.
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: P,
+    protocol: Protocol,
+    idle_timeout: Option<Duration>,
+    connections: Arc<Connections>,
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + 'static> KvsServer<E, P> {
+    /// Creates a server speaking the framed kvs protocol.
+    pub fn new(engine: E, pool: P) -> KvsServer<E, P> {
+        KvsServer {
+            engine,
+            pool,
+            protocol: Protocol::Kvs,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            connections: Arc::new(Connections::default()),
+        }
+    }
+
+    pub fn protocol(mut self, protocol: Protocol) -> KvsServer<E, P> {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Closes connections that send no request for `timeout`, one second
+    /// unless set. `None` keeps them open until the client hangs up, each
+    /// holding a thread of the pool all the while.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> KvsServer<E, P> {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Listens on `addr` and serves connections until the process exits.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        self.serve_listener(listener, &AtomicBool::new(false));
+        Ok(())
+    }
+
+    /// Listens on `addr` and serves connections on a background thread
+    /// until the returned handle is stopped. The engine is dropped, and so
+    /// closed, once the server has stopped.
+    ///
+    /// Binding to port 0 picks a free port, which `ServerHandle::addr`
+    /// reports.
+    pub fn start<A: ToSocketAddrs>(self, addr: A) -> Result<ServerHandle> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let connections = Arc::clone(&self.connections);
+        let thread = {
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || self.serve_listener(listener, &stopped))
+        };
+        Ok(ServerHandle {
+            addr,
+            stopped,
+            connections,
+            thread,
+        })
+    }
+
+    fn serve_listener(&self, listener: TcpListener, stopped: &AtomicBool) {
+        let busy_replies = Arc::new(AtomicUsize::new(0));
+        for stream in listener.incoming() {
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Accept failed: {}", err);
+                    continue;
+                }
+            };
+            let busy = match stream.try_clone() {
+                Ok(busy) => busy,
+                Err(err) => {
+                    error!("Connection error: {}", err);
+                    continue;
+                }
+            };
+            let open = match self.connections.open(&stream) {
+                Some(open) => open,
+                // The server is stopping.
+                None => break,
+            };
+            let engine = self.engine.clone();
+            let protocol = self.protocol;
+            let idle_timeout = self.idle_timeout;
+            let spawned = self.pool.try_spawn(move || {
+                let served = match protocol {
+                    Protocol::Kvs => serve(stream, &engine, idle_timeout),
+                    Protocol::Resp => serve_resp(stream, &engine, idle_timeout),
+                };
+                if let Err(err) = served {
+                    error!("Connection error: {}", err);
+                }
+                drop(open);
+            });
+            if spawned.is_err() {
+                turn_away(busy, protocol, &busy_replies);
+            }
+        }
+    }
+}
+
+/// A server started with `KvsServer::start`.
+pub struct ServerHandle {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    connections: Arc<Connections>,
+    thread: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// The address the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops the server and closes the engine.
+    ///
+    /// No more connections are accepted and no more requests are read.
+    /// Requests that were already read are answered, for at most
+    /// `deadline`; connections still busy after that are cut off.
+    pub fn stop(self, deadline: Duration) -> Result<()> {
+        self.stopped.store(true, Ordering::SeqCst);
+        // The listener only looks at the flag once `accept` returns, so
+        // give it a connection to return.
+        TcpStream::connect(self.addr)?;
+        self.connections.close(deadline);
+        // Joining drops the server, and with it the pool and the engine.
+        self.thread
+            .join()
+            .map_err(|_| KvError::IoError("Server thread panicked".to_owned()))
+    }
+}
+
+/// The connections a server is serving, so it can close them to stop.
+#[derive(Default)]
+struct Connections {
+    state: Mutex<ConnectionsState>,
+    closed: Condvar,
+}
+
+#[derive(Default)]
+struct ConnectionsState {
+    open: HashMap<u64, TcpStream>,
+    next_id: u64,
+    closing: bool,
+}
+
+impl Connections {
+    /// Tracks `stream` until the returned guard is dropped, or returns
+    /// `None` if the server is stopping.
+    fn open(self: &Arc<Self>, stream: &TcpStream) -> Option<OpenConnection> {
+        let stream = match stream.try_clone() {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("Connection error: {}", err);
+                return None;
+            }
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.closing {
+            return None;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.open.insert(id, stream);
+        Some(OpenConnection {
+            connections: Arc::clone(self),
+            id,
+        })
+    }
+
+    /// Stops every connection from reading further requests, waits up to
+    /// `deadline` for them to finish, then shuts down whichever are left.
+    fn close(&self, deadline: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.closing = true;
+        for stream in state.open.values() {
+            // Fails only if the client already hung up.
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+
+        let until = Instant::now() + deadline;
+        while !state.open.is_empty() {
+            let now = Instant::now();
+            if now >= until {
+                warn!("Cutting off {} connections at shutdown", state.open.len());
+                for stream in state.open.values() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                return;
+            }
+            state = self.closed.wait_timeout(state, until - now).unwrap().0;
+        }
+    }
+}
+
+/// Removes a connection from `Connections` when it is done.
+struct OpenConnection {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        let mut state = self.connections.state.lock().unwrap();
+        state.open.remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
+
+/// Sends a busy reply on a thread of its own, so that waiting for the
+/// client to hang up never holds up the accept loop. `pending` counts the
+/// replies still waiting.
+fn turn_away(stream: TcpStream, protocol: Protocol, pending: &Arc<AtomicUsize>) {
+    if pending.fetch_add(1, Ordering::SeqCst) >= MAX_BUSY_PENDING {
+        pending.fetch_sub(1, Ordering::SeqCst);
+        warn!("Too many busy replies pending, closing connection");
+        return;
+    }
+    let pending = Arc::clone(pending);
+    thread::spawn(move || {
+        if let Err(err) = reply_busy(stream, protocol) {
+            error!("Connection error: {}", err);
+        }
+        pending.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Turns a connection away because the pool's queue is full.
+///
+/// On the kvs protocol the reply carries id 0, the id of the first
+/// request a client sends on a new connection.
+fn reply_busy(stream: TcpStream, protocol: Protocol) -> Result<()> {
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    match protocol {
+        Protocol::Kvs => {
+            let result = KvsResult::Error(KvError::Busy);
+            write_frame(&mut writer, &Response { id: 0, result })?;
+        }
+        Protocol::Resp => {
+            resp::write_value(&mut writer, &resp::Value::Error("ERR server busy".to_owned()))?;
+        }
+    }
+    writer.flush()?;
+
+    // Closing with the client's request still unread would reset the
+    // connection, and the client could lose the reply. Give it a moment
+    // to see the reply and hang up.
+    stream.shutdown(Shutdown::Write)?;
+    stream.set_read_timeout(Some(BUSY_LINGER))?;
+    let _ = io::copy(&mut (&stream).take(MAX_BUSY_DRAIN), &mut io::sink());
+    Ok(())
+}
+
+/// Answers requests on `stream` until the client hangs up or goes idle.
+fn serve<E: KvsEngine>(
+    stream: TcpStream,
+    engine: &E,
+    idle_timeout: Option<Duration>,
+) -> Result<()> {
+    stream.set_read_timeout(idle_timeout)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    while wait_for_request(&mut reader)? {
+        let request = match read_frame::<_, Request>(&mut reader)? {
+            Some(request) => request,
+            None => break,
+        };
+        debug!("Receive: {:?}", request);
+        let result = execute(request.command, engine);
+        write_frame(&mut writer, &Response { id: request.id, result })?;
+        // Responses to pipelined requests go out together, once every
+        // request that has already arrived is answered.
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Answers RESP requests on `stream` until the client hangs up or goes
+/// idle.
+fn serve_resp<E: KvsEngine>(
+    stream: TcpStream,
+    engine: &E,
+    idle_timeout: Option<Duration>,
+) -> Result<()> {
+    stream.set_read_timeout(idle_timeout)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    while wait_for_request(&mut reader)? {
+        let request = match resp::read_value(&mut reader)? {
+            Some(request) => request,
+            None => break,
+        };
+        debug!("Receive: {:?}", request);
+        let reply = resp::execute(request, engine);
+        resp::write_value(&mut writer, &reply)?;
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Waits for the next request to start arriving. Returns `false` once the
+/// client has hung up, or has sent nothing for the read timeout.
+fn wait_for_request(reader: &mut BufReader<TcpStream>) -> Result<bool> {
+    match reader.fill_buf() {
+        Ok(buf) => Ok(!buf.is_empty()),
+        // Socket timeouts surface as either, depending on the platform.
+        Err(ref err)
+            if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut =>
+        {
+            debug!("Closing idle connection");
+            Ok(false)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub(crate) fn execute<E: KvsEngine>(command: KvsCommand, store: &E) -> KvsResult {
+    match command {
+        KvsCommand::Set(key, value, ttl) => match store.set_with_ttl(key, value, ttl) {
+            Err(e) => KvsResult::Error(e),
+            _ => KvsResult::Ok,
+        },
+        KvsCommand::Remove(key) => match store.remove(key) {
+            Err(e) => KvsResult::Error(e),
+            _ => KvsResult::Ok,
+        },
+        KvsCommand::Get(key) => match store.get(key) {
+            Ok(v) => match v {
+                Some(value) => KvsResult::Some(value),
+                None => KvsResult::None,
+            },
+            Err(e) => KvsResult::Error(e),
+        },
+        KvsCommand::Batch(ops) => match store.write_batch(ops) {
+            Err(e) => KvsResult::Error(e),
+            _ => KvsResult::Ok,
+        },
+        KvsCommand::CompareAndSwap(key, expected, new) => {
+            match store.compare_and_swap(key, expected, new) {
+                Ok(swapped) => KvsResult::Swapped(swapped),
+                Err(e) => KvsResult::Error(e),
+            }
+        }
+        KvsCommand::Ttl(key) => match store.ttl(key) {
+            Ok(ttl) => KvsResult::Ttl(ttl),
+            Err(e) => KvsResult::Error(e),
+        },
+        KvsCommand::Scan(start, end, limit) => match store.scan(start, end, limit) {
+            Ok(pairs) => KvsResult::Pairs(pairs),
+            Err(e) => KvsResult::Error(e),
+        },
+    }
+}
@@ -1,17 +1,95 @@
 mod naive;
 mod rayon;
 mod shared_queue;
+mod slots;
+mod task;
+mod work_stealing;
+
+use std::panic::{self, AssertUnwindSafe};
 
 use super::Result;
 
 pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
 pub use self::shared_queue::SharedQueueThreadPool;
+pub use self::task::{Panic, Scope, TaskHandle};
+pub use self::work_stealing::WorkStealingThreadPool;
 
+/// What a pool does with a job when its queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueuePolicy {
+    /// Wait for room in the queue.
+    Block,
+    /// Turn the job away with `KvError::Busy`.
+    Reject,
+    /// Run the job on the thread that spawned it.
+    CallerRuns,
+}
 
 pub trait ThreadPool {
+    /// Creates a pool whose queue has no limit.
     fn new(threads: u32) -> Result<Self> where Self: Sized;
 
-    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static;
-}
+    /// Creates a pool that holds at most `capacity` jobs waiting for a
+    /// thread, and applies `policy` to any more.
+    fn with_queue(threads: u32, capacity: usize, policy: QueuePolicy) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// The policy the pool was created with.
+    fn policy(&self) -> QueuePolicy;
+
+    /// Runs `job` on the pool, applying `policy` when the queue is full.
+    /// Only `QueuePolicy::Reject` makes this fail, with `KvError::Busy`.
+    fn submit<F>(&self, job: F, policy: QueuePolicy) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static;
 
+    /// Runs `job` on the pool. This never turns a job away: with
+    /// `QueuePolicy::Reject` it waits for room as `QueuePolicy::Block` does.
+    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        let policy = match self.policy() {
+            QueuePolicy::Reject => QueuePolicy::Block,
+            policy => policy,
+        };
+        // Only `QueuePolicy::Reject` fails.
+        let _ = self.submit(job, policy);
+    }
+
+    /// Runs `job` on the pool, applying the queue policy when the queue
+    /// is full. Only `QueuePolicy::Reject` makes this fail, with
+    /// `KvError::Busy`.
+    fn try_spawn<F>(&self, job: F) -> Result<()> where F: FnOnce() + Send + 'static {
+        self.submit(job, self.policy())
+    }
+
+    /// Like `spawn`, but returns a handle to wait for the job's result.
+    /// A panic in the job is caught and returned by `TaskHandle::join`.
+    fn spawn_with_handle<F, T>(&self, job: F) -> TaskHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = task::with_handle(job);
+        self.spawn(job);
+        handle
+    }
+
+    /// Runs `f` with a `Scope` whose jobs may borrow anything that outlives
+    /// this call, and waits for all of them before returning.
+    fn scope<'env, F, R>(&self, f: F) -> R
+    where
+        Self: Sized,
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env, Self>) -> R,
+    {
+        let scope = Scope::new(self);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        // Even if `f` panicked, jobs it spawned may still be using what it
+        // lent them.
+        scope.wait();
+        match result {
+            Ok(value) => value,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
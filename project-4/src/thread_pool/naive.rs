@@ -1,17 +1,64 @@
 use super::ThreadPool;
+use std::sync::Arc;
 use std::thread;
 
-use super::Result;
-
-pub struct NaiveThreadPool;
+use super::slots::{check_capacity, Admission, Slots};
+use super::{QueuePolicy, Result};
 
+/// Starts a thread for every job.
+///
+/// Having no queue, its capacity instead limits how many jobs run at once.
+pub struct NaiveThreadPool {
+    slots: Arc<Slots>,
+    policy: QueuePolicy,
+}
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(threads: u32) -> Result<Self> where Self: Sized {
-        Ok(NaiveThreadPool)
+    fn new(_threads: u32) -> Result<Self> where Self: Sized {
+        Ok(NaiveThreadPool {
+            slots: Arc::new(Slots::new(None)),
+            policy: QueuePolicy::Block,
+        })
+    }
+
+    fn with_queue(_threads: u32, capacity: usize, policy: QueuePolicy) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        check_capacity(capacity)?;
+        Ok(NaiveThreadPool {
+            slots: Arc::new(Slots::new(Some(capacity))),
+            policy,
+        })
+    }
+
+    fn policy(&self) -> QueuePolicy {
+        self.policy
+    }
+
+    fn submit<F>(&self, job: F, policy: QueuePolicy) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.slots.acquire(policy)? {
+            Admission::RunHere => job(),
+            Admission::Queued => {
+                let slot = Slot(Arc::clone(&self.slots));
+                thread::spawn(move || {
+                    job();
+                    drop(slot);
+                });
+            }
+        }
+        Ok(())
     }
+}
+
+/// Gives a slot back when the job's thread finishes, even by panicking.
+struct Slot(Arc<Slots>);
 
-    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
-        thread::spawn(job);
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.release();
     }
-}
\ No newline at end of file
+}
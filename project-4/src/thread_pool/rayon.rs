@@ -1,17 +1,72 @@
 use super::ThreadPool;
-use std::thread;
+use std::sync::Arc;
 
-use super::Result;
+use log::error;
 
-pub struct RayonThreadPool;
+use super::slots::{check_capacity, check_threads, Admission, Slots};
+use super::{QueuePolicy, Result};
+use crate::KvError;
 
+/// A pool backed by rayon's work-stealing scheduler.
+///
+/// Rayon's queues have no limit, so the capacity counts the jobs spawned
+/// here that no thread has picked up yet.
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+    slots: Arc<Slots>,
+    policy: QueuePolicy,
+}
+
+impl RayonThreadPool {
+    fn build(threads: u32, slots: Slots, policy: QueuePolicy) -> Result<Self> {
+        // Rayon would take 0 to mean one thread per CPU.
+        check_threads(threads)?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .thread_name(|i| format!("kvs-rayon-{}", i))
+            // Without a handler rayon aborts the process when a job panics.
+            .panic_handler(|_| error!("Thread pool job panicked"))
+            .build()
+            .map_err(|err| KvError::IoError(err.to_string()))?;
+        Ok(RayonThreadPool {
+            pool,
+            slots: Arc::new(slots),
+            policy,
+        })
+    }
+}
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> where Self: Sized {
-        Ok(RayonThreadPool)
+        RayonThreadPool::build(threads, Slots::new(None), QueuePolicy::Block)
+    }
+
+    fn with_queue(threads: u32, capacity: usize, policy: QueuePolicy) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        check_capacity(capacity)?;
+        RayonThreadPool::build(threads, Slots::new(Some(capacity)), policy)
+    }
+
+    fn policy(&self) -> QueuePolicy {
+        self.policy
     }
 
-    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
-        thread::spawn(job);
+    fn submit<F>(&self, job: F, policy: QueuePolicy) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.slots.acquire(policy)? {
+            Admission::RunHere => job(),
+            Admission::Queued => {
+                let slots = Arc::clone(&self.slots);
+                self.pool.spawn(move || {
+                    slots.release();
+                    job();
+                });
+            }
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+}
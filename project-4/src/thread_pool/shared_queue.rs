@@ -1,17 +1,126 @@
 use super::ThreadPool;
 use std::thread;
 
-use super::Result;
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use crossbeam::sync::WaitGroup;
+use log::error;
 
-pub struct SharedQueueThreadPool;
+use super::slots::{check_capacity, check_threads};
+use super::{QueuePolicy, Result};
+use crate::KvError;
 
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of workers taking jobs from one shared queue.
+///
+/// A worker whose job panics is replaced, so the pool never shrinks.
+/// Dropping the pool lets the workers finish every job already queued and
+/// waits for them to exit.
+pub struct SharedQueueThreadPool {
+    sender: Option<Sender<Job>>,
+    workers: Option<WaitGroup>,
+    policy: QueuePolicy,
+}
+
+impl SharedQueueThreadPool {
+    fn start(
+        threads: u32,
+        (sender, receiver): (Sender<Job>, Receiver<Job>),
+        policy: QueuePolicy,
+    ) -> Result<Self> {
+        check_threads(threads)?;
+        let workers = WaitGroup::new();
+        for _ in 0..threads {
+            Worker::spawn(receiver.clone(), workers.clone());
+        }
+        Ok(SharedQueueThreadPool {
+            sender: Some(sender),
+            workers: Some(workers),
+            policy,
+        })
+    }
+}
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(threads: u32) -> Result<Self> where Self: Sized {
-        Ok(SharedQueueThreadPool)
+        SharedQueueThreadPool::start(threads, channel::unbounded(), QueuePolicy::Block)
+    }
+
+    fn with_queue(threads: u32, capacity: usize, policy: QueuePolicy) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        check_capacity(capacity)?;
+        SharedQueueThreadPool::start(threads, channel::bounded(capacity), policy)
+    }
+
+    fn policy(&self) -> QueuePolicy {
+        self.policy
+    }
+
+    fn submit<F>(&self, job: F, policy: QueuePolicy) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let job: Job = Box::new(job);
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return Ok(()),
+        };
+        // The workers only hang up once the pool is dropped.
+        match policy {
+            QueuePolicy::Block => sender.send(job).expect("thread pool has no workers"),
+            _ => match sender.try_send(job) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) if policy == QueuePolicy::Reject => {
+                    return Err(KvError::Busy)
+                }
+                Err(TrySendError::Full(job)) => job(),
+                Err(TrySendError::Disconnected(_)) => panic!("thread pool has no workers"),
+            },
+        }
+        Ok(())
     }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        // Closing the queue makes each worker exit once it is empty.
+        self.sender.take();
+        if let Some(workers) = self.workers.take() {
+            workers.wait();
+        }
+    }
+}
+
+/// Runs jobs on its own thread. If a job panics, dropping the worker
+/// during the unwind starts a replacement on the same queue.
+struct Worker {
+    receiver: Receiver<Job>,
+    running: WaitGroup,
+}
+
+impl Worker {
+    fn spawn(receiver: Receiver<Job>, running: WaitGroup) {
+        let worker = Worker {
+            receiver,
+            running,
+        };
+        thread::spawn(move || worker.run());
+    }
+
+    fn run(self) {
+        while let Ok(job) = self.receiver.recv() {
+            job();
+        }
+    }
+}
 
-    fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
-        thread::spawn(job);
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            error!("Thread pool job panicked, starting a new worker");
+            Worker::spawn(self.receiver.clone(), self.running.clone());
+        }
     }
 }
@@ -0,0 +1,70 @@
+use std::sync::{Condvar, Mutex};
+
+use super::QueuePolicy;
+use crate::{KvError, Result};
+
+/// Counts jobs against a pool's queue capacity, for pools whose queue
+/// can't be bounded directly.
+pub(super) struct Slots {
+    capacity: Option<usize>,
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// What to do with a job after asking `Slots` for room.
+pub(super) enum Admission {
+    /// A slot was taken; give it back with `Slots::release`.
+    Queued,
+    RunHere,
+}
+
+impl Slots {
+    pub(super) fn new(capacity: Option<usize>) -> Slots {
+        Slots {
+            capacity,
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Takes a slot, or applies `policy` if there is none.
+    pub(super) fn acquire(&self, policy: QueuePolicy) -> Result<Admission> {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return Ok(Admission::Queued),
+        };
+        let mut used = self.used.lock().unwrap();
+        while *used >= capacity {
+            match policy {
+                QueuePolicy::Block => used = self.freed.wait(used).unwrap(),
+                QueuePolicy::Reject => return Err(KvError::Busy),
+                QueuePolicy::CallerRuns => return Ok(Admission::RunHere),
+            }
+        }
+        *used += 1;
+        Ok(Admission::Queued)
+    }
+
+    pub(super) fn release(&self) {
+        if self.capacity.is_some() {
+            *self.used.lock().unwrap() -= 1;
+            self.freed.notify_one();
+        }
+    }
+}
+
+/// Refuses a pool that could never run a job.
+pub(super) fn check_threads(threads: u32) -> Result<()> {
+    if threads == 0 {
+        return Err(KvError::IoError("A thread pool needs at least one thread".to_owned()));
+    }
+    Ok(())
+}
+
+/// Refuses a queue that could never take a job.
+pub(super) fn check_capacity(capacity: usize) -> Result<()> {
+    if capacity == 0 {
+        return Err(KvError::IoError("A job queue needs room for at least one job".to_owned()));
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::any::Any;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+
+use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam::sync::WaitGroup;
+
+use super::ThreadPool;
+
+/// A job's panic, caught so it can be handed to whoever waits on the job.
+pub struct Panic(Box<dyn Any + Send + 'static>);
+
+impl Panic {
+    /// The message the job panicked with, if it was a string.
+    pub fn message(&self) -> Option<&str> {
+        match self.0.downcast_ref::<&'static str>() {
+            Some(message) => Some(message),
+            None => self.0.downcast_ref::<String>().map(String::as_str),
+        }
+    }
+
+    /// The value the job panicked with, as `std::panic::catch_unwind`
+    /// returns it.
+    pub fn into_payload(self) -> Box<dyn Any + Send + 'static> {
+        self.0
+    }
+}
+
+impl fmt::Debug for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.message() {
+            Some(message) => write!(f, "Panic({:?})", message),
+            None => write!(f, "Panic(..)"),
+        }
+    }
+}
+
+impl fmt::Display for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.message() {
+            Some(message) => write!(f, "Job panicked: {}", message),
+            None => write!(f, "Job panicked"),
+        }
+    }
+}
+
+/// Waits for a job started with `spawn_with_handle` or `Scope::spawn`.
+pub struct TaskHandle<T> {
+    receiver: Receiver<Result<T, Panic>>,
+}
+
+impl<T> TaskHandle<T> {
+    /// Blocks until the job is done and returns what it returned, or how
+    /// it panicked.
+    pub fn join(self) -> Result<T, Panic> {
+        match self.receiver.recv() {
+            Ok(result) => result,
+            Err(_) => Err(Panic(Box::new("job was dropped before it ran"))),
+        }
+    }
+}
+
+/// Wraps `job` so its result, or its panic, is sent to the returned handle.
+/// Catching the panic here keeps the worker that runs it alive.
+pub(super) fn with_handle<'a, F, T>(job: F) -> (impl FnOnce() + Send + 'a, TaskHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'a,
+    T: Send + 'a,
+{
+    let (sender, receiver): (Sender<Result<T, Panic>>, _) = channel::bounded(1);
+    let job = move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(job)).map_err(Panic);
+        // Nobody may be waiting on the handle any more.
+        let _ = sender.send(result);
+    };
+    (job, TaskHandle { receiver })
+}
+
+/// Spawns jobs that may borrow from the stack of the `ThreadPool::scope`
+/// caller. `scope` only returns once every one of them has finished.
+pub struct Scope<'scope, 'env: 'scope, P: ThreadPool> {
+    pool: &'scope P,
+    running: WaitGroup,
+    // Invariant in 'env, like `std::thread::Scope`.
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope, 'env, P: ThreadPool> Scope<'scope, 'env, P> {
+    pub(super) fn new(pool: &'scope P) -> Self {
+        Scope {
+            pool,
+            running: WaitGroup::new(),
+            _env: PhantomData,
+        }
+    }
+
+    pub fn spawn<F, T>(&self, job: F) -> TaskHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'env,
+        T: Send + 'env,
+    {
+        let (job, handle) = with_handle(job);
+        let running = self.running.clone();
+        let job: Box<dyn FnOnce() + Send + 'env> = Box::new(move || {
+            job();
+            drop(running);
+        });
+        // Safe because `wait` blocks the end of the scope until `running`
+        // is dropped, which happens only once the job has run or been
+        // dropped, so nothing it borrows is gone while it can still use it.
+        let job: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(job) };
+        self.pool.spawn(job);
+        handle
+    }
+
+    pub(super) fn wait(self) {
+        self.running.wait();
+    }
+}
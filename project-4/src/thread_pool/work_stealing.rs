@@ -0,0 +1,161 @@
+use super::ThreadPool;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::deque::{Injector, Stealer, Worker};
+use log::error;
+
+use super::slots::{check_capacity, check_threads, Admission, Slots};
+use super::{QueuePolicy, Result};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// How long an idle worker sleeps before looking for work to steal again.
+/// Spawning wakes a worker right away; this only bounds how long a backlog
+/// on a busy worker's deque can go unnoticed.
+const IDLE_WAIT: Duration = Duration::from_millis(10);
+
+/// Workers with a deque each, stealing from one another when they run out.
+///
+/// Spawned jobs go to a shared injector queue. A worker takes a batch of
+/// them at a time onto its own deque, so most pops touch no shared state,
+/// and a worker whose deque is empty steals from the others. Panics are
+/// caught on the worker, which keeps its deque.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+    policy: QueuePolicy,
+}
+
+struct Shared {
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    slots: Slots,
+    stopping: AtomicBool,
+    idle: Mutex<()>,
+    wake: Condvar,
+}
+
+impl WorkStealingThreadPool {
+    fn start(threads: u32, slots: Slots, policy: QueuePolicy) -> Result<Self> {
+        check_threads(threads)?;
+        let locals: Vec<_> = (0..threads).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: locals.iter().map(Worker::stealer).collect(),
+            slots,
+            stopping: AtomicBool::new(false),
+            idle: Mutex::new(()),
+            wake: Condvar::new(),
+        });
+        let workers = locals
+            .into_iter()
+            .enumerate()
+            .map(|(i, local)| {
+                let shared = Arc::clone(&shared);
+                thread::Builder::new()
+                    .name(format!("kvs-stealing-{}", i))
+                    .spawn(move || shared.run(local))
+            })
+            .collect::<std::io::Result<_>>()?;
+        Ok(WorkStealingThreadPool {
+            shared,
+            workers,
+            policy,
+        })
+    }
+}
+
+impl Shared {
+    fn run(&self, local: Worker<Job>) {
+        loop {
+            match self.find_job(&local) {
+                Some(job) => {
+                    self.slots.release();
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        error!("Thread pool job panicked");
+                    }
+                }
+                None => {
+                    let idle = self.idle.lock().unwrap();
+                    if !self.injector.is_empty() {
+                        continue;
+                    }
+                    // Jobs still queued when the pool is dropped are run
+                    // first.
+                    if self.stopping.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    let _ = self.wake.wait_timeout(idle, IDLE_WAIT).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Pops the local deque, then takes a batch from the injector, then
+    /// tries to steal from the other workers.
+    fn find_job(&self, local: &Worker<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(|steal| steal.success())
+        })
+    }
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: u32) -> Result<Self> where Self: Sized {
+        WorkStealingThreadPool::start(threads, Slots::new(None), QueuePolicy::Block)
+    }
+
+    fn with_queue(threads: u32, capacity: usize, policy: QueuePolicy) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        check_capacity(capacity)?;
+        WorkStealingThreadPool::start(threads, Slots::new(Some(capacity)), policy)
+    }
+
+    fn policy(&self) -> QueuePolicy {
+        self.policy
+    }
+
+    fn submit<F>(&self, job: F, policy: QueuePolicy) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let job: Job = Box::new(job);
+        match self.shared.slots.acquire(policy)? {
+            Admission::RunHere => job(),
+            Admission::Queued => {
+                self.shared.injector.push(job);
+                // Taking the lock orders this with a worker that just found
+                // the injector empty and is about to wait.
+                let _idle = self.shared.idle.lock().unwrap();
+                self.shared.wake.notify_one();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        {
+            let _idle = self.shared.idle.lock().unwrap();
+            self.shared.wake.notify_all();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
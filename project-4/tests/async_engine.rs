@@ -0,0 +1,65 @@
+use futures::future::{self, Future};
+use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool};
+use kvs::{AsyncKvsEngine, KvError, KvStore, KvsEngine, SledKvsEngine};
+use std::ops::Bound;
+use tempfile::TempDir;
+
+fn get_set_remove<E: KvsEngine>(store: AsyncKvsEngine<E, SharedQueueThreadPool>) {
+    store
+        .set("key1".to_owned(), "value1".to_owned())
+        .wait()
+        .unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).wait().unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(store
+        .compare_and_swap("key1".to_owned(), Some("value1".to_owned()), None)
+        .wait()
+        .unwrap());
+    assert_eq!(store.get("key1".to_owned()).wait().unwrap(), None);
+    match store.remove("key1".to_owned()).wait() {
+        Err(KvError::KeyNotFound) => (),
+        result => panic!("expected KeyNotFound, got {:?}", result),
+    }
+}
+
+// Calls resolve to what the engine returns, errors included.
+#[test]
+fn async_get_set_remove_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    get_set_remove(AsyncKvsEngine::<KvStore, _>::open(temp_dir.path(), 2).unwrap());
+}
+
+#[test]
+fn async_get_set_remove_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    get_set_remove(AsyncKvsEngine::<SledKvsEngine, _>::open(temp_dir.path(), 2).unwrap());
+}
+
+// Many calls can be in flight at once, and the writes land in the store
+// the facade wraps.
+#[test]
+fn async_calls_in_flight() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = AsyncKvsEngine::<KvStore, RayonThreadPool>::open(temp_dir.path(), 4).unwrap();
+
+    let sets = (0..100).map(|i| store.set(format!("key{}", i), format!("value{}", i)));
+    future::join_all(sets).wait().unwrap();
+
+    let gets = (0..100).map(|i| store.get(format!("key{}", i)));
+    let values = future::join_all(gets).wait().unwrap();
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(value, Some(format!("value{}", i)));
+    }
+
+    let pairs = store
+        .scan(Bound::Unbounded, Bound::Unbounded, Some(3))
+        .wait()
+        .unwrap();
+    assert_eq!(pairs.len(), 3);
+    assert_eq!(
+        store.engine().get("key42".to_owned()).unwrap(),
+        Some("value42".to_owned())
+    );
+}
@@ -1,7 +1,9 @@
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::Write;
+use std::net::TcpStream;
+use std::process::{Child, Command, ExitStatus, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -78,6 +80,14 @@ fn client_cli_invalid_set() {
         .assert()
         .failure();
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key", "value", "--ttl", "6000000000000000h"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid ttl"));
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["get", "key", "--unknown-flag"])
@@ -164,6 +174,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -171,6 +182,32 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// Errors the server logs show up on stderr without any configuration.
+#[test]
+fn cli_logs_errors() {
+    let addr = "127.0.0.1:4017";
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(&["--engine", "kvs", "--addr", addr])
+        .env_remove("RUST_LOG")
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"not a kvs frame\n").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Connection error"));
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -184,6 +221,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -203,6 +241,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -212,18 +251,94 @@ fn cli_wrong_engine() {
     }
 }
 
+/// Sends `name` to the server and waits for it to exit.
+fn signal(mut child: Child, name: &str) -> ExitStatus {
+    Command::new("kill")
+        .args(&[format!("-{}", name), child.id().to_string()])
+        .status()
+        .unwrap();
+    child.wait().unwrap()
+}
+
+// The server shuts down cleanly on SIGINT and SIGTERM, exiting 0.
+#[test]
+fn cli_graceful_shutdown() {
+    for (name, addr) in &[("INT", "127.0.0.1:4010"), ("TERM", "127.0.0.1:4011")] {
+        let temp_dir = TempDir::new().unwrap();
+        let stderr_path = temp_dir.path().join("stderr");
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let child = cmd
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key1", "value1", "--addr", addr])
+            .assert()
+            .success();
+        assert!(signal(child, name).success());
+
+        let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+        assert!(content.contains("shutting down"));
+    }
+}
+
+// The async runtime turns requests away once its queue is full, by
+// default too, rather than blocking a runtime thread or running disk I/O
+// on it.
+#[test]
+fn cli_async_runtime_needs_reject_policy() {
+    let temp_dir = TempDir::new().unwrap();
+    for policy in &["block", "caller-runs"] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&[
+                "--addr",
+                "127.0.0.1:4016",
+                "--runtime",
+                "async",
+                "--queue-capacity",
+                "4",
+                "--queue-policy",
+                policy,
+            ])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+
+    let addr = "127.0.0.1:4016";
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let child = cmd
+        .args(&["--addr", addr, "--runtime", "async", "--queue-capacity", "4"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    assert!(signal(child, "TERM").success());
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
-    let mut child = server
+    let child = server
         .args(&["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
-        child.kill().expect("server exited before killed");
+        assert!(signal(child, "TERM").success());
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -291,20 +406,76 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value4", "--ttl", "1h", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("s\n"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("No expiry\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key3", "--expected", "value3", "--new", "value5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Value changed"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key3", "--expected", "value4", "--new", "value5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value5\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "key", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\n");
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
     // Reopen and check value
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
-    let mut child = server
+    let child = server
         .args(&["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
-        child.kill().expect("server exited before killed");
+        assert!(signal(child, "TERM").success());
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -335,3 +506,34 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// The memory engine serves requests without touching the working directory.
+#[test]
+fn cli_access_server_memory_engine() {
+    let addr = "127.0.0.1:4015";
+    let temp_dir = TempDir::new().unwrap();
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    assert!(signal(child, "TERM").success());
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
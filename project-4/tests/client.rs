@@ -0,0 +1,140 @@
+use assert_cmd::prelude::*;
+use futures::Future;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{AsyncKvsClient, KvError, KvStore, KvsClient, KvsClientOptions, KvsEngine, KvsServer};
+use std::net::TcpListener;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+// The client library talks to a running server and reports engine errors
+// as the same `KvError`.
+#[test]
+fn client_against_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::connect("127.0.0.1:4009").unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(client
+        .compare_and_swap("key1".to_owned(), Some("value1".to_owned()), None)
+        .unwrap());
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    match client.remove("key1".to_owned()) {
+        Err(KvError::KeyNotFound) => (),
+        result => panic!("expected KeyNotFound, got {:?}", result),
+    }
+
+    // The connection is reused after an error result.
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// A server that never answers makes a request fail with `Timeout` instead
+// of hanging.
+#[test]
+fn client_read_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let options = KvsClientOptions {
+        read_timeout: Some(Duration::from_millis(200)),
+        ..KvsClientOptions::default()
+    };
+    let client = KvsClient::connect_with_options(addr, options).unwrap();
+    let (_stream, _) = listener.accept().unwrap();
+
+    match client.get("key1".to_owned()) {
+        Err(KvError::Timeout) => (),
+        result => panic!("expected Timeout, got {:?}", result),
+    }
+}
+
+// A pooled connection the server has since closed is replaced rather than
+// failing the next request.
+#[test]
+fn client_replaces_closed_connection() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let server = KvsServer::new(engine, pool)
+        .idle_timeout(Some(Duration::from_millis(100)))
+        .start("127.0.0.1:0")
+        .unwrap();
+    let options = KvsClientOptions {
+        idle_timeout: None,
+        ..KvsClientOptions::default()
+    };
+    let client = KvsClient::connect_with_options(server.addr(), options).unwrap();
+
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    drop(client);
+    server.stop(Duration::from_secs(5)).unwrap();
+}
+
+// Connecting to a port nobody listens on is reported right away.
+#[test]
+fn client_connect_refused() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+
+    assert!(KvsClient::connect(addr).is_err());
+}
+
+// The async client hands itself back with every result, and keeps its
+// connection across errors the server reports.
+#[test]
+fn async_client_against_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let server = KvsServer::new(engine, pool).start("127.0.0.1:0").unwrap();
+
+    let calls = AsyncKvsClient::connect(server.addr())
+        .and_then(|client| client.set("key1".to_owned(), "value1".to_owned()))
+        .and_then(|(result, client)| {
+            result.unwrap();
+            client.get("key1".to_owned())
+        })
+        .and_then(|(result, client)| {
+            assert_eq!(result.unwrap(), Some("value1".to_owned()));
+            client.remove("key2".to_owned())
+        })
+        .and_then(|(result, client)| {
+            match result {
+                Err(KvError::KeyNotFound) => (),
+                result => panic!("expected KeyNotFound, got {:?}", result),
+            }
+            client.get("key2".to_owned())
+        })
+        .map(|(result, _)| assert_eq!(result.unwrap(), None));
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(calls).unwrap();
+
+    server.stop(Duration::from_secs(5)).unwrap();
+}
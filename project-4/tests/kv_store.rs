@@ -1,23 +1,33 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    Durability, KvError, KvStore, KvStoreOptions, KvsEngine, MemKvsEngine, Op, Result,
+    SledKvsEngine,
+};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
 // Should get previously stored value
-#[test]
-fn get_stored_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-
+fn check_get_stored_value<E: KvsEngine>(store: E) -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
 
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_get_stored_value(KvStore::open(temp_dir.path())?)?;
 
     // Open from disk again and check persistent data
-    drop(store);
     let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
@@ -25,19 +35,26 @@ fn get_stored_value() -> Result<()> {
     Ok(())
 }
 
-// Should overwrite existent value
 #[test]
-fn overwrite_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+fn get_stored_value_memory() -> Result<()> {
+    check_get_stored_value(MemKvsEngine::new())
+}
 
+// Should overwrite existent value
+fn check_overwrite_value<E: KvsEngine>(store: E) -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     store.set("key1".to_owned(), "value2".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn overwrite_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_overwrite_value(KvStore::open(temp_dir.path())?)?;
 
     // Open from disk again and check persistent data
-    drop(store);
     let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
@@ -46,17 +63,24 @@ fn overwrite_value() -> Result<()> {
     Ok(())
 }
 
-// Should get `None` when getting a non-existent key
 #[test]
-fn get_non_existent_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+fn overwrite_value_memory() -> Result<()> {
+    check_overwrite_value(MemKvsEngine::new())
+}
 
+// Should get `None` when getting a non-existent key
+fn check_get_non_existent_value<E: KvsEngine>(store: E) -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn get_non_existent_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_get_non_existent_value(KvStore::open(temp_dir.path())?)?;
 
     // Open from disk again and check persistent data
-    drop(store);
     let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
@@ -64,23 +88,59 @@ fn get_non_existent_value() -> Result<()> {
 }
 
 #[test]
-fn remove_non_existent_key() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+fn get_non_existent_value_memory() -> Result<()> {
+    check_get_non_existent_value(MemKvsEngine::new())
+}
+
+fn check_remove_non_existent_key<E: KvsEngine>(store: E) -> Result<()> {
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
 
 #[test]
-fn remove_key() -> Result<()> {
+fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    check_remove_non_existent_key(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn remove_non_existent_key_memory() -> Result<()> {
+    check_remove_non_existent_key(MemKvsEngine::new())
+}
+
+fn check_remove_key<E: KvsEngine>(store: E) -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_remove_key(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn remove_key_memory() -> Result<()> {
+    check_remove_key(MemKvsEngine::new())
+}
+
+// Clones share their keys, while every open starts out empty.
+#[test]
+fn memory_is_shared_but_not_persisted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MemKvsEngine::open(temp_dir.path())?;
+    store.clone().set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    let store = MemKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 0);
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -91,9 +151,12 @@ fn compaction() -> Result<()> {
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
         let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
+            // Background compaction may remove a file between listing and
+            // stat.
+            .filter_map(|res| match res.and_then(|entry| entry.metadata()) {
+                Ok(metadata) => Some(Ok(metadata.len())),
+                Err(err) if err.io_error().map(io::Error::kind) == Some(io::ErrorKind::NotFound) => None,
+                Err(err) => Some(Err(err)),
             })
             .sum();
         len.expect("fail to get directory size")
@@ -127,10 +190,660 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Overwrite a single key until stale bytes pass the configured ratio and
+// the directory shrinks, without ever reaching the absolute threshold.
 #[test]
-fn concurrent_set() -> Result<()> {
+fn compaction_by_stale_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: u64::MAX,
+        compaction_ratio: Some(1.0),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            // Background compaction may remove a file between listing and
+            // stat.
+            .filter_map(|res| match res.and_then(|entry| entry.metadata()) {
+                Ok(metadata) => Some(Ok(metadata.len())),
+                Err(err) if err.io_error().map(io::Error::kind) == Some(io::ErrorKind::NotFound) => None,
+                Err(err) => Some(Err(err)),
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..100_000 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+
+        let new_size = dir_size();
+        if new_size >= current_size {
+            current_size = new_size;
+            continue;
+        }
+
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key".to_owned())?, Some(format!("{}", iter)));
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// The newest log file that has anything in it.
+fn last_log_file(dir: &Path) -> PathBuf {
+    WalkDir::new(dir)
+        .into_iter()
+        .map(|entry| entry.expect("fail to walk directory").into_path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .filter(|path| fs::metadata(path).map(|m| m.len() > 0).unwrap_or(false))
+        .max_by_key(|path| {
+            path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+        })
+        .expect("no log file found")
+}
+
+// Simulate a crash in the middle of appending a record: the partial record
+// is cut off on open and everything written before it survives.
+#[test]
+fn recover_torn_tail() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = last_log_file(temp_dir.path());
+    let intact_len = fs::metadata(&log).expect("unable to stat log").len();
+    let first_record = fs::read(&log).expect("unable to read log");
+    let mut file = OpenOptions::new().append(true).open(&log).expect("unable to open log");
+    // An intact header followed by only part of its body.
+    file.write_all(&first_record[..13 + 3])
+        .expect("unable to write log");
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log).expect("unable to stat log").len(), intact_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Simulate a power loss that extended the log with zeros the data never
+// reached: the zeros are cut off like any other torn tail.
+#[test]
+fn recover_zeroed_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = last_log_file(temp_dir.path());
+    let intact_len = fs::metadata(&log).expect("unable to stat log").len();
+    let mut file = OpenOptions::new().append(true).open(&log).expect("unable to open log");
+    file.write_all(&[0; 4096]).expect("unable to write log");
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log).expect("unable to stat log").len(), intact_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Damage a record that has more records behind it: opening must fail with a
+// checksum error instead of silently dropping data.
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = last_log_file(temp_dir.path());
+    let mut file = OpenOptions::new().write(true).open(&log).expect("unable to open log");
+    // Flip the last byte of the first record's value.
+    file.seek(SeekFrom::Start(13 + 8 + 4 + 8 + 5))
+        .expect("unable to seek log");
+    file.write_all(b"X").expect("unable to write log");
+    drop(file);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Corrupted { offset, .. }) => assert_eq!(offset, 0),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("corruption not detected"),
+    }
+
+    Ok(())
+}
+
+// Damage the length of a record in the middle of the newest generation:
+// the header checksum catches it, rather than the rest of the log being
+// taken for a torn write and cut off.
+#[test]
+fn detect_corrupted_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let log = last_log_file(temp_dir.path());
+    let log_len = fs::metadata(&log).expect("unable to stat log").len();
+    let record_len = log_len / 10;
+    let mut file = OpenOptions::new().write(true).open(&log).expect("unable to open log");
+    // Make the third record's length run far past the end of the file.
+    file.seek(SeekFrom::Start(2 * record_len + 2))
+        .expect("unable to seek log");
+    file.write_all(&[0x80]).expect("unable to write log");
+    drop(file);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Corrupted { offset, .. }) => assert_eq!(offset, 2 * record_len),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    assert_eq!(fs::metadata(&log).expect("unable to stat log").len(), log_len);
+
+    Ok(())
+}
+
+// A directory created by one engine must be refused by the other.
+#[test]
+fn open_with_wrong_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+    match SledKvsEngine::open(temp_dir.path()) {
+        Err(KvError::WrongEngine { expected, found }) => {
+            assert_eq!(expected, "sled");
+            assert_eq!(found, "kvs");
+        }
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("opened a kvs directory with sled"),
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(SledKvsEngine::open(temp_dir.path())?);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::WrongEngine { expected, found }) => {
+            assert_eq!(expected, "kvs");
+            assert_eq!(found, "sled");
+        }
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("opened a sled directory with kvs"),
+    }
+
+    Ok(())
+}
+
+// A manifest from a newer version must not be opened.
+#[test]
+fn open_unsupported_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("MANIFEST"),
+        r#"{"engine":"kvs","format_version":99,"parameters":{}}"#,
+    )
+    .expect("unable to write manifest");
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::UnsupportedVersion { version, .. }) => assert_eq!(version, 99),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("opened an unsupported version"),
+    }
+
+    Ok(())
+}
+
+// A directory from before manifests and generations, holding a single JSON
+// log, is upgraded on open.
+#[test]
+fn migrate_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("db"),
+        concat!(
+            "{\"Set\":[\"\",\"\"]}\n",
+            "{\"Set\":[\"key1\",\"value1\"]}\n",
+            "{\"Set\":[\"key2\",\"value2\"]}\n",
+            "{\"Remove\":\"key1\"}\n",
+        ),
+    )
+    .expect("unable to write legacy log");
+    fs::write(temp_dir.path().join("db-count"), "{\"Set\":[\"count\",\"4\"]}\n")
+        .expect("unable to write legacy count");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_dir.path().join("db").exists());
+    assert!(!temp_dir.path().join("db-count").exists());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Every durability mode keeps the data it acknowledged.
+#[test]
+fn durability_modes() -> Result<()> {
+    let modes = [
+        Durability::Never,
+        Durability::EveryWrite,
+        Durability::Interval(10),
+        Durability::GroupCommit,
+    ];
+    for &durability in modes.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            durability,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+
+    Ok(())
+}
+
+// Writers waiting at the same time share syncs under group commit, so
+// concurrent writes take fewer syncs than there are writes.
+#[test]
+fn group_commit_shares_syncs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        durability: Durability::GroupCommit,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..50 {
+                    store.set(format!("key{}-{}", t, i), "value".to_owned()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert!(store.syncs() < 8 * 50, "{} syncs", store.syncs());
+    for t in 0..8 {
+        assert_eq!(store.get(format!("key{}-49", t))?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Scans return keys in order, honour both kinds of bounds and the limit, and
+// skip removed keys.
+fn check_scan<E: KvsEngine>(store: E) -> Result<()> {
+    for key in &["b", "a", "ab", "abc", "b\u{10FFFF}", "c", "ac"] {
+        store.set(key.to_string(), format!("{}-value", key))?;
+    }
+    store.remove("ac".to_owned())?;
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs
+            .into_iter()
+            .map(|(key, value)| {
+                assert_eq!(value, format!("{}-value", key));
+                key
+            })
+            .collect()
+    };
+
+    assert_eq!(
+        keys(store.scan(Bound::Unbounded, Bound::Unbounded, None)?),
+        vec!["a", "ab", "abc", "b", "b\u{10FFFF}", "c"]
+    );
+    assert_eq!(
+        keys(store.scan(Bound::Included("ab".to_owned()), Bound::Excluded("b".to_owned()), None)?),
+        vec!["ab", "abc"]
+    );
+    assert_eq!(
+        keys(store.scan(Bound::Excluded("ab".to_owned()), Bound::Included("b".to_owned()), None)?),
+        vec!["abc", "b"]
+    );
+    assert_eq!(
+        keys(store.scan(Bound::Included("a".to_owned()), Bound::Unbounded, Some(2))?),
+        vec!["a", "ab"]
+    );
+    assert!(store
+        .scan(Bound::Included("c".to_owned()), Bound::Excluded("a".to_owned()), None)?
+        .is_empty());
+
+    assert_eq!(keys(store.scan_prefix("ab".to_owned(), None)?), vec!["ab", "abc"]);
+    assert_eq!(keys(store.scan_prefix("b".to_owned(), None)?), vec!["b", "b\u{10FFFF}"]);
+    assert_eq!(keys(store.scan_prefix(String::new(), Some(1))?), vec!["a"]);
+    Ok(())
+}
+
+#[test]
+fn scan_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn scan_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn scan_memory() -> Result<()> {
+    check_scan(MemKvsEngine::new())
+}
+
+// Keys set with a time-to-live disappear once it has passed, and setting
+// them again without one makes them permanent.
+fn check_expiry<E: KvsEngine>(store: E) -> Result<()> {
+    store.set_with_ttl("short".to_owned(), "1".to_owned(), Some(Duration::from_millis(200)))?;
+    store.set_with_ttl("long".to_owned(), "2".to_owned(), Some(Duration::from_secs(3600)))?;
+    store.set("forever".to_owned(), "3".to_owned())?;
+
+    let ttl = store.ttl("short".to_owned())?.expect("short has a ttl");
+    assert!(ttl <= Duration::from_millis(200));
+    assert_eq!(store.ttl("forever".to_owned())?, None);
+    match store.ttl("missing".to_owned()) {
+        Err(KvError::KeyNotFound) => (),
+        _ => panic!("ttl of a missing key should fail"),
+    }
+    assert_eq!(store.get("short".to_owned())?, Some("1".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("short".to_owned())?, None);
+    match store.ttl("short".to_owned()) {
+        Err(KvError::KeyNotFound) => (),
+        _ => panic!("ttl of an expired key should fail"),
+    }
+    match store.remove("short".to_owned()) {
+        Err(KvError::KeyNotFound) => (),
+        _ => panic!("removing an expired key should fail"),
+    }
+    let keys: Vec<String> = store
+        .scan(Bound::Unbounded, Bound::Unbounded, None)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, vec!["forever", "long"]);
+
+    store.set_with_ttl("long".to_owned(), "4".to_owned(), None)?;
+    assert_eq!(store.ttl("long".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("4".to_owned()));
+
+    // A time-to-live too long to count in milliseconds never runs out,
+    // this one exactly 2^64 of them.
+    let ttl = Some(Duration::new(18_446_744_073_709_551, 616_000_000));
+    store.set_with_ttl("huge".to_owned(), "5".to_owned(), ttl)?;
+    assert_eq!(store.get("huge".to_owned())?, Some("5".to_owned()));
+    assert!(store.ttl("huge".to_owned())?.is_some());
+    Ok(())
+}
+
+#[test]
+fn expiry_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_expiry(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn expiry_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_expiry(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn expiry_memory() -> Result<()> {
+    check_expiry(MemKvsEngine::new())
+}
+
+// Expiry times are absolute, so they carry over a restart.
+#[test]
+fn expiry_survives_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("short".to_owned(), "1".to_owned(), Some(Duration::from_millis(200)))?;
+    store.set_with_ttl("long".to_owned(), "2".to_owned(), Some(Duration::from_secs(3600)))?;
+    drop(store);
+
+    thread::sleep(Duration::from_millis(300));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    let ttl = store.ttl("long".to_owned())?.expect("long has a ttl");
+    assert!(ttl > Duration::from_secs(3500));
+    Ok(())
+}
+
+// Once the sweeper has dropped expired keys, their bytes count towards
+// compaction and go away with it.
+#[test]
+fn expired_entries_are_compacted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: 16 * 1024,
+        sweep_interval: Duration::from_millis(10),
+        ..KvStoreOptions::default()
+    };
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            // Background compaction may remove a file between listing and
+            // stat.
+            .filter_map(|res| match res.and_then(|entry| entry.metadata()) {
+                Ok(metadata) => Some(Ok(metadata.len())),
+                Err(err) if err.io_error().map(io::Error::kind) == Some(io::ErrorKind::NotFound) => None,
+                Err(err) => Some(Err(err)),
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..1000 {
+        let key = format!("key{}", key_id);
+        store.set_with_ttl(key, "value".repeat(4), Some(Duration::from_millis(100)))?;
+    }
+    thread::sleep(Duration::from_millis(300));
+    let expired_size = dir_size();
+
+    // The next write finds enough stale bytes to start a compaction, and
+    // dropping the store waits for it.
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    assert!(dir_size() < expired_size);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// The sweeper goes by each key's latest deadline: a key set again with a
+// later one, or none, outlives the deadline it had before.
+#[test]
+fn sweep_follows_latest_deadline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sweep_interval: Duration::from_millis(10),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key in &["later", "forever", "gone"] {
+        store.set_with_ttl(key.to_string(), "1".to_owned(), Some(Duration::from_millis(100)))?;
+    }
+    store.set_with_ttl("later".to_owned(), "2".to_owned(), Some(Duration::from_secs(3600)))?;
+    store.set("forever".to_owned(), "2".to_owned())?;
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("later".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.get("forever".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.get("gone".to_owned())?, None);
+    Ok(())
+}
+
+// A batch applies all of its operations in order, or none of them when one
+// can't be applied.
+fn check_batch<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.write_batch(vec![
+        Op::Set("b".to_owned(), "2".to_owned(), None),
+        Op::Set("c".to_owned(), "3".to_owned(), Some(Duration::from_secs(3600))),
+        Op::Remove("a".to_owned()),
+    ])?;
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert_eq!(store.get("b".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.get("c".to_owned())?, Some("3".to_owned()));
+    assert!(store.ttl("c".to_owned())?.is_some());
+
+    match store.write_batch(vec![
+        Op::Set("d".to_owned(), "4".to_owned(), None),
+        Op::Remove("missing".to_owned()),
+    ]) {
+        Err(KvError::KeyNotFound) => (),
+        _ => panic!("a batch removing a missing key should fail"),
+    }
+    assert_eq!(store.get("d".to_owned())?, None);
+
+    store.write_batch(vec![
+        Op::Set("e".to_owned(), "5".to_owned(), None),
+        Op::Remove("e".to_owned()),
+    ])?;
+    assert_eq!(store.get("e".to_owned())?, None);
+    store.write_batch(Vec::new())?;
+    Ok(())
+}
+
+#[test]
+fn batch_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batch(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert_eq!(store.get("b".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.get("d".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn batch_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batch(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn batch_memory() -> Result<()> {
+    check_batch(MemKvsEngine::new())
+}
+
+// Simulate a crash partway through writing a batch: on open none of it is
+// applied, even the operations that made it to disk in full.
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let intact_len = fs::metadata(last_log_file(temp_dir.path()))
+        .expect("unable to stat log")
+        .len();
+    store.write_batch(vec![
+        Op::Set("key1".to_owned(), "value2".to_owned(), None),
+        Op::Set("key2".to_owned(), "value2".to_owned(), None),
+    ])?;
+    drop(store);
+
+    let log = last_log_file(temp_dir.path());
+    let len = fs::metadata(&log).expect("unable to stat log").len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)
+        .expect("unable to open log")
+        .set_len(len - 1)
+        .expect("unable to truncate log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log).expect("unable to stat log").len(), intact_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Swaps only happen when the current value, or its absence, matches.
+fn check_compare_and_swap<E: KvsEngine>(store: E) -> Result<()> {
+    let key = || "key".to_owned();
+    let value = |v: &str| Some(v.to_owned());
+
+    assert!(!store.compare_and_swap(key(), value("1"), value("2"))?);
+    assert!(store.compare_and_swap(key(), None, value("1"))?);
+    assert!(!store.compare_and_swap(key(), None, value("2"))?);
+    assert!(!store.compare_and_swap(key(), value("2"), value("3"))?);
+    assert_eq!(store.get(key())?, value("1"));
+
+    assert!(store.compare_and_swap(key(), value("1"), value("2"))?);
+    assert_eq!(store.get(key())?, value("2"));
+    assert!(store.compare_and_swap(key(), value("2"), None)?);
+    assert_eq!(store.get(key())?, None);
+    assert!(store.compare_and_swap(key(), None, None)?);
+
+    // An expired key counts as absent.
+    store.set_with_ttl(key(), "4".to_owned(), Some(Duration::from_millis(100)))?;
+    thread::sleep(Duration::from_millis(200));
+    assert!(!store.compare_and_swap(key(), value("4"), value("5"))?);
+    assert!(store.compare_and_swap(key(), None, value("5"))?);
+    assert_eq!(store.ttl(key())?, None);
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn compare_and_swap_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn compare_and_swap_memory() -> Result<()> {
+    check_compare_and_swap(MemKvsEngine::new())
+}
+
+fn check_concurrent_set<E: KvsEngine>(store: E) -> Result<()> {
     let barrier = Arc::new(Barrier::new(1001));
     for i in 0..1000 {
         let store = store.clone();
@@ -147,9 +860,15 @@ fn concurrent_set() -> Result<()> {
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
+    Ok(())
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_set(KvStore::open(temp_dir.path())?)?;
 
     // Open from disk again and check persistent data
-    drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
@@ -159,36 +878,62 @@ fn concurrent_set() -> Result<()> {
 }
 
 #[test]
-fn concurrent_get() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-    for i in 0..100 {
-        store
-            .set(format!("key{}", i), format!("value{}", i))
-            .unwrap();
-    }
+fn concurrent_set_memory() -> Result<()> {
+    check_concurrent_set(MemKvsEngine::new())
+}
 
+// Threads incrementing a counter with compare-and-swap never lose an update.
+fn check_concurrent_compare_and_swap<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("counter".to_owned(), "0".to_owned())?;
     let mut handles = Vec::new();
-    for thread_id in 0..100 {
+    for _ in 0..8 {
         let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
+        handles.push(thread::spawn(move || {
+            for _ in 0..100 {
+                loop {
+                    let current = store.get("counter".to_owned()).unwrap().unwrap();
+                    let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                    if store
+                        .compare_and_swap("counter".to_owned(), Some(current), Some(next))
+                        .unwrap()
+                    {
+                        break;
+                    }
+                }
             }
-        });
-        handles.push(handle);
+        }));
     }
     for handle in handles {
         handle.join().unwrap();
     }
 
-    // Open from disk again and check persistent data
-    drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_compare_and_swap(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn concurrent_compare_and_swap_memory() -> Result<()> {
+    check_concurrent_compare_and_swap(MemKvsEngine::new())
+}
+
+fn check_concurrent_get<E: KvsEngine>(store: E) -> Result<()> {
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    get_concurrently(&store);
+    Ok(())
+}
+
+/// Reads back the keys `check_concurrent_get` set from 100 threads at once.
+fn get_concurrently<E: KvsEngine>(store: &E) {
     let mut handles = Vec::new();
     for thread_id in 0..100 {
         let store = store.clone();
@@ -206,6 +951,20 @@ fn concurrent_get() -> Result<()> {
     for handle in handles {
         handle.join().unwrap();
     }
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_get(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    get_concurrently(&KvStore::open(temp_dir.path())?);
 
     Ok(())
 }
+
+#[test]
+fn concurrent_get_memory() -> Result<()> {
+    check_concurrent_get(MemKvsEngine::new())
+}
@@ -0,0 +1,100 @@
+use assert_cmd::prelude::*;
+use kvs::protocol::{read_frame, write_frame, Connection, Request, Response, PROTOCOL_VERSION};
+use kvs::{KvError, KvsCommand, KvsResult};
+use std::io::Cursor;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Frames read back as what was written, one after the other.
+#[test]
+fn frame_round_trip() {
+    let mut buf = Vec::new();
+    for id in 0..3 {
+        let request = Request {
+            id,
+            command: KvsCommand::Get(format!("key{}", id)),
+        };
+        write_frame(&mut buf, &request).unwrap();
+    }
+    assert_eq!(buf[0], PROTOCOL_VERSION);
+
+    let mut reader = Cursor::new(buf);
+    for id in 0..3 {
+        let request: Request = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(request.id, id);
+        match request.command {
+            KvsCommand::Get(key) => assert_eq!(key, format!("key{}", id)),
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+    assert!(read_frame::<_, Request>(&mut reader).unwrap().is_none());
+}
+
+// Frames from another protocol version, or claiming an absurd length, are
+// refused.
+#[test]
+fn frame_rejects_bad_header() {
+    let mut reader = Cursor::new(vec![PROTOCOL_VERSION + 1, 0, 0, 0, 0]);
+    match read_frame::<_, Response>(&mut reader) {
+        Err(KvError::ProtocolError(_)) => (),
+        _ => panic!("a frame of another version should be refused"),
+    }
+
+    let mut reader = Cursor::new(vec![PROTOCOL_VERSION, 255, 255, 255, 255]);
+    match read_frame::<_, Response>(&mut reader) {
+        Err(KvError::ProtocolError(_)) => (),
+        _ => panic!("an oversized frame should be refused"),
+    }
+}
+
+// A single connection serves many requests, including ones sent before any
+// response was read.
+#[test]
+fn pipelined_requests() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut conn = Connection::connect("127.0.0.1:4006").unwrap();
+    let mut ids = Vec::new();
+    for i in 0..100 {
+        let command = KvsCommand::Set(format!("key{}", i), format!("value{}", i), None);
+        ids.push(conn.send(command).unwrap());
+    }
+    for i in 0..100 {
+        ids.push(conn.send(KvsCommand::Get(format!("key{}", i))).unwrap());
+    }
+    conn.flush().unwrap();
+
+    for (n, id) in ids.into_iter().enumerate() {
+        let response = conn.receive().unwrap();
+        assert_eq!(response.id, id);
+        match (n, response.result) {
+            (n, KvsResult::Ok) if n < 100 => (),
+            (n, KvsResult::Some(value)) if n >= 100 => {
+                assert_eq!(value, format!("value{}", n - 100))
+            }
+            (_, result) => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    match conn.call(KvsCommand::Remove("key0".to_owned())).unwrap() {
+        KvsResult::Ok => (),
+        result => panic!("unexpected result {:?}", result),
+    }
+    match conn.call(KvsCommand::Get("key0".to_owned())).unwrap() {
+        KvsResult::None => (),
+        result => panic!("unexpected result {:?}", result),
+    }
+    drop(conn);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
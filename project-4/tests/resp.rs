@@ -0,0 +1,199 @@
+use assert_cmd::prelude::*;
+use bytes::BytesMut;
+use kvs::resp::{read_value, RespCodec, Value};
+use kvs::KvError;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::codec::Decoder;
+
+// A hand-written RESP client: every command goes out as an array of bulk
+// strings and the raw reply bytes are compared.
+struct RespClient {
+    stream: TcpStream,
+}
+
+impl RespClient {
+    fn send(&mut self, args: &[&str]) {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.stream.write_all(request.as_bytes()).unwrap();
+    }
+
+    fn expect(&mut self, reply: &str) {
+        let mut buf = vec![0; reply.len()];
+        self.stream.read_exact(&mut buf).unwrap();
+        assert_eq!(String::from_utf8_lossy(&buf), reply);
+    }
+
+    fn call(&mut self, args: &[&str], reply: &str) {
+        self.send(args);
+        self.expect(reply);
+    }
+}
+
+fn resp_access_server(engine: &str, addr: &str, runtime: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--engine",
+            engine,
+            "--addr",
+            addr,
+            "--protocol",
+            "resp",
+            "--runtime",
+            runtime,
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = RespClient {
+        stream: TcpStream::connect(addr).unwrap(),
+    };
+    client.call(&["PING"], "+PONG\r\n");
+    client.call(&["ping", "hello"], "$5\r\nhello\r\n");
+    client.call(&["GET", "key1"], "$-1\r\n");
+    client.call(&["SET", "key1", "value1"], "+OK\r\n");
+    client.call(&["GET", "key1"], "$6\r\nvalue1\r\n");
+    client.call(&["SET", "key2", "value2", "EX", "3600"], "+OK\r\n");
+    client.call(&["EXISTS", "key1", "key2", "key3"], ":2\r\n");
+    client.call(&["DEL", "key1", "key3"], ":1\r\n");
+    client.call(&["EXISTS", "key1"], ":0\r\n");
+    client.call(&["SET", "key3", "value3", "PX", "100"], "+OK\r\n");
+    thread::sleep(Duration::from_millis(200));
+    client.call(&["GET", "key3"], "$-1\r\n");
+
+    client.call(&["GET"], "-ERR wrong number of arguments for 'get' command\r\n");
+    client.call(&["FLUSHALL"], "-ERR unknown command 'FLUSHALL'\r\n");
+
+    // Pipelined commands are answered in order.
+    client.send(&["SET", "key4", "value4"]);
+    client.send(&["GET", "key4"]);
+    client.send(&["DEL", "key4"]);
+    client.expect("+OK\r\n$6\r\nvalue4\r\n:1\r\n");
+
+    // So are inline commands, as typed into telnet.
+    client.stream.write_all(b"GET key2\r\n").unwrap();
+    client.expect("$6\r\nvalue2\r\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn resp_access_server_kvs_engine() {
+    resp_access_server("kvs", "127.0.0.1:4007", "threaded");
+}
+
+#[test]
+fn resp_access_server_sled_engine() {
+    resp_access_server("sled", "127.0.0.1:4008", "threaded");
+}
+
+#[test]
+fn resp_access_async_server() {
+    resp_access_server("kvs", "127.0.0.1:4012", "async");
+}
+
+// Arrays inside arrays are refused at the first level of nesting, however
+// deep the client goes.
+#[test]
+fn resp_rejects_nested_arrays() {
+    let request = "*1\r\n".repeat(100_000);
+    match read_value(&mut request.as_bytes()) {
+        Err(KvError::ProtocolError(_)) => (),
+        result => panic!("expected a protocol error, got {:?}", result),
+    }
+    match RespCodec::default().decode(&mut BytesMut::from(request.as_bytes())) {
+        Err(KvError::ProtocolError(_)) => (),
+        result => panic!("expected a protocol error, got {:?}", result),
+    }
+
+    let request = b"*2\r\n$3\r\nGET\r\n:1\r\n";
+    assert_eq!(
+        read_value(&mut &request[..]).unwrap(),
+        Some(Value::Array(Some(vec![
+            Value::BulkString(Some(b"GET".to_vec())),
+            Value::Integer(1),
+        ])))
+    );
+}
+
+// A bulk string is only taken once all of it has arrived, and lengths past
+// the limit are refused outright.
+#[test]
+fn resp_bulk_string_lengths() {
+    let mut buf = BytesMut::from(&b"*1\r\n$60000000\r\nGET"[..]);
+    assert_eq!(RespCodec::default().decode(&mut buf).unwrap(), None);
+    assert_eq!(buf.len(), 18);
+
+    let mut short = &b"$60000000\r\nGET"[..];
+    match read_value(&mut short) {
+        Err(KvError::ProtocolError(_)) => (),
+        result => panic!("expected a protocol error, got {:?}", result),
+    }
+
+    let mut buf = BytesMut::from(&b"$536870912\r\n"[..]);
+    match RespCodec::default().decode(&mut buf) {
+        Err(KvError::ProtocolError(_)) => (),
+        result => panic!("expected a protocol error, got {:?}", result),
+    }
+}
+
+// A long bulk string received a little at a time is only parsed once it
+// has all arrived; parsing it again on every read would take hours here.
+#[test]
+fn resp_bulk_string_in_chunks() {
+    let len = 16 * 1024 * 1024;
+    let mut request = format!("*2\r\n$4\r\nPING\r\n${}\r\n", len).into_bytes();
+    request.extend(vec![b'x'; len]);
+    request.extend_from_slice(b"\r\n");
+
+    let mut codec = RespCodec::default();
+    let mut buf = BytesMut::new();
+    let mut value = None;
+    for chunk in request.chunks(1024) {
+        assert_eq!(value, None);
+        buf.extend_from_slice(chunk);
+        value = codec.decode(&mut buf).unwrap();
+    }
+    assert_eq!(
+        value,
+        Some(Value::Array(Some(vec![
+            Value::BulkString(Some(b"PING".to_vec())),
+            Value::BulkString(Some(vec![b'x'; len])),
+        ])))
+    );
+    assert!(buf.is_empty());
+}
+
+// Lines that never end are refused once past their limit, rather than
+// buffered for as long as the client keeps sending.
+#[test]
+fn resp_line_lengths() {
+    let inline = vec![b'a'; 100 * 1024];
+    let header = [&b"*"[..], &[b'1'; 40][..]].concat();
+    for line in &[inline, header] {
+        match read_value(&mut &line[..]) {
+            Err(KvError::ProtocolError(_)) => (),
+            result => panic!("expected a protocol error, got {:?}", result),
+        }
+        let mut buf = BytesMut::from(&line[..]);
+        match RespCodec::default().decode(&mut buf) {
+            Err(KvError::ProtocolError(_)) => (),
+            result => panic!("expected a protocol error, got {:?}", result),
+        }
+    }
+
+    let mut buf = BytesMut::from(&b"PING hello"[..]);
+    assert_eq!(RespCodec::default().decode(&mut buf).unwrap(), None);
+}
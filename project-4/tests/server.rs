@@ -0,0 +1,271 @@
+use kvs::protocol::Connection;
+use kvs::thread_pool::{QueuePolicy, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AsyncKvsServer, KvError, KvStore, KvsClient, KvsCommand, KvsEngine, KvsResult, KvsServer,
+    SledKvsEngine,
+};
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn concurrent_clients<E: KvsEngine>(engine: E) {
+    let pool = SharedQueueThreadPool::new(4).unwrap();
+    let server = KvsServer::new(engine, pool).start("127.0.0.1:0").unwrap();
+    let addr = server.addr();
+
+    run_clients(addr);
+
+    server.stop(Duration::from_secs(5)).unwrap();
+    assert!(KvsClient::connect(addr).is_err());
+}
+
+/// Sets and gets keys from several clients at once.
+fn run_clients(addr: SocketAddr) {
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            thread::spawn(move || {
+                let client = KvsClient::connect(addr).unwrap();
+                for i in 0..50 {
+                    let key = format!("key{}-{}", t, i);
+                    client.set(key.clone(), format!("value{}", i)).unwrap();
+                    assert_eq!(client.get(key).unwrap(), Some(format!("value{}", i)));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let client = KvsClient::connect(addr).unwrap();
+    for t in 0..8 {
+        assert_eq!(
+            client.get(format!("key{}-49", t)).unwrap(),
+            Some("value49".to_owned())
+        );
+    }
+}
+
+// Several clients are served at once by an in-process server, which stops
+// accepting connections once stopped.
+#[test]
+fn concurrent_clients_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    concurrent_clients(KvStore::open(temp_dir.path()).unwrap());
+}
+
+#[test]
+fn concurrent_clients_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    concurrent_clients(SledKvsEngine::open(temp_dir.path()).unwrap());
+}
+
+// Once every thread is busy and the queue is full, further connections
+// get a busy reply.
+#[test]
+fn busy_server_rejects_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::with_queue(1, 1, QueuePolicy::Reject).unwrap();
+    let server = KvsServer::new(engine, pool).start("127.0.0.1:0").unwrap();
+
+    // Each client keeps its connection open, one on the only thread and
+    // one in the queue.
+    let served = KvsClient::connect(server.addr()).unwrap();
+    served.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let queued = KvsClient::connect(server.addr()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let rejected = KvsClient::connect(server.addr()).unwrap();
+    match rejected.get("key1".to_owned()) {
+        Err(KvError::Busy) => (),
+        result => panic!("expected Busy, got {:?}", result),
+    }
+    assert_eq!(
+        served.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    drop((served, queued, rejected));
+    server.stop(Duration::from_secs(5)).unwrap();
+}
+
+// Busy replies wait for their clients to hang up off the accept loop, so
+// clients that never do hold up neither each other's replies nor the
+// connections accepted after them.
+#[test]
+fn busy_replies_dont_block_accepting() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::with_queue(1, 1, QueuePolicy::Reject).unwrap();
+    let server = KvsServer::new(engine, pool)
+        .idle_timeout(None)
+        .start("127.0.0.1:0")
+        .unwrap();
+
+    let served = KvsClient::connect(server.addr()).unwrap();
+    served.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let queued = TcpStream::connect(server.addr()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    let mut lingering: Vec<_> = (0..20)
+        .map(|_| TcpStream::connect(server.addr()).unwrap())
+        .collect();
+    for stream in &mut lingering {
+        assert!(stream.read(&mut [0; 64]).unwrap() > 0);
+    }
+    // Give the only thread a moment to see both hang up.
+    drop((served, queued));
+    thread::sleep(Duration::from_millis(100));
+    let client = KvsClient::connect(server.addr()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    drop((lingering, client));
+    server.stop(Duration::from_secs(5)).unwrap();
+}
+
+// A connection that sends nothing is closed after the idle timeout, so it
+// holds the only thread of the pool for no longer than that.
+#[test]
+fn idle_connection_is_closed() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let server = KvsServer::new(engine, pool)
+        .idle_timeout(Some(Duration::from_millis(200)))
+        .start("127.0.0.1:0")
+        .unwrap();
+
+    let mut idle = TcpStream::connect(server.addr()).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    let start = Instant::now();
+    let client = KvsClient::connect(server.addr()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(idle.read(&mut [0]).unwrap(), 0);
+
+    drop(client);
+    server.stop(Duration::from_secs(5)).unwrap();
+}
+
+// Stopping closes idle connections rather than waiting for their clients,
+// and closes the engine so the data directory can be opened again.
+#[test]
+fn stop_closes_connections_and_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let server = KvsServer::new(engine, pool).start("127.0.0.1:0").unwrap();
+
+    let client = KvsClient::connect(server.addr()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let start = Instant::now();
+    server.stop(Duration::from_secs(5)).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        engine.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// The async server serves many clients at once too, with engine calls on
+// a pool smaller than the number of clients.
+#[test]
+fn async_concurrent_clients() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let server = AsyncKvsServer::new(engine, pool)
+        .start("127.0.0.1:0")
+        .unwrap();
+    let addr = server.addr();
+
+    run_clients(addr);
+
+    server.stop(Duration::from_secs(5)).unwrap();
+    assert!(KvsClient::connect(addr).is_err());
+}
+
+// Idle connections hold no thread, so a single-threaded pool keeps
+// answering while hundreds of them are open, and pipelined requests on
+// one connection run in order.
+#[test]
+fn async_idle_and_pipelined_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let server = AsyncKvsServer::new(engine, pool)
+        .start("127.0.0.1:0")
+        .unwrap();
+
+    let idle: Vec<_> = (0..200)
+        .map(|_| TcpStream::connect(server.addr()).unwrap())
+        .collect();
+
+    let mut conn = Connection::connect(server.addr()).unwrap();
+    let commands = vec![
+        KvsCommand::Set("key1".to_owned(), "value1".to_owned(), None),
+        KvsCommand::Get("key1".to_owned()),
+        KvsCommand::Remove("key1".to_owned()),
+        KvsCommand::Get("key1".to_owned()),
+    ];
+    let ids: Vec<u64> = commands
+        .into_iter()
+        .map(|command| conn.send(command).unwrap())
+        .collect();
+    conn.flush().unwrap();
+    let responses: Vec<_> = ids.iter().map(|_| conn.receive().unwrap()).collect();
+    assert_eq!(
+        responses
+            .iter()
+            .map(|response| response.id)
+            .collect::<Vec<_>>(),
+        ids
+    );
+    match &responses[1].result {
+        KvsResult::Some(value) => assert_eq!(value, "value1"),
+        result => panic!("expected value1, got {:?}", result),
+    }
+    match &responses[3].result {
+        KvsResult::None => (),
+        result => panic!("expected None, got {:?}", result),
+    }
+
+    drop((idle, conn));
+    server.stop(Duration::from_secs(5)).unwrap();
+}
+
+// Stopping the async server closes idle connections and the engine, the
+// same as stopping `KvsServer`.
+#[test]
+fn async_stop_closes_connections_and_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let server = AsyncKvsServer::new(engine, pool)
+        .start("127.0.0.1:0")
+        .unwrap();
+
+    let client = KvsClient::connect(server.addr()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let start = Instant::now();
+    server.stop(Duration::from_secs(5)).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        engine.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
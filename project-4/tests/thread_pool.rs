@@ -1,9 +1,12 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvError, Result};
 
+use crossbeam::channel::{self, Receiver};
 use crossbeam_utils::sync::WaitGroup;
 
 fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
@@ -68,3 +71,264 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+// Dropping the pool runs every job already queued before returning.
+fn drop_drains_queue<P: ThreadPool>() -> Result<()> {
+    const TASK_NUM: usize = 100;
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let pool = P::new(2)?;
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(1));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+    drop(pool);
+
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_drop_drains_queue() -> Result<()> {
+    drop_drains_queue::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+// Handles return what each job returned, or how it panicked.
+fn spawn_with_handle<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let handles: Vec<_> = (0..100).map(|i| pool.spawn_with_handle(move || i * 2)).collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join().unwrap(), i * 2);
+    }
+
+    let handle = pool.spawn_with_handle(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!("boom");
+    });
+    let panic = handle.join().unwrap_err();
+    assert_eq!(panic.message(), Some("boom"));
+
+    // The pool is still usable afterwards.
+    assert_eq!(pool.spawn_with_handle(|| 1).join().unwrap(), 1);
+    Ok(())
+}
+
+// Scoped jobs borrow from the caller's stack, and are all done once
+// `scope` returns.
+fn scope<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let mut chunks = vec![vec![1; 100]; 10];
+    let counter = AtomicUsize::new(0);
+
+    let total = pool.scope(|scope| {
+        let handles: Vec<_> = chunks
+            .iter_mut()
+            .map(|chunk| {
+                let counter = &counter;
+                scope.spawn(move || {
+                    thread::sleep(Duration::from_millis(1));
+                    for n in chunk.iter_mut() {
+                        *n += 1;
+                    }
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    chunk.iter().sum::<usize>()
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).sum::<usize>()
+    });
+    assert_eq!(total, 2000);
+
+    // Jobs whose handles were dropped have finished too.
+    pool.scope(|scope| {
+        for chunk in chunks.iter_mut() {
+            let counter = &counter;
+            scope.spawn(move || {
+                thread::sleep(Duration::from_millis(1));
+                chunk.clear();
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+    });
+    assert!(chunks.iter().all(|chunk| chunk.is_empty()));
+    assert_eq!(counter.load(Ordering::SeqCst), 20);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_scope() -> Result<()> {
+    scope::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_scope() -> Result<()> {
+    scope::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_scope() -> Result<()> {
+    scope::<RayonThreadPool>()
+}
+
+/// A job that waits for `gate` to close, then counts itself in `done`.
+fn gated(gate: &Receiver<()>, done: &Arc<AtomicUsize>) -> impl FnOnce() + Send + 'static {
+    let gate = gate.clone();
+    let done = Arc::clone(done);
+    move || {
+        let _ = gate.recv();
+        done.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+// A full queue turns jobs away, runs them on the caller, or makes the
+// caller wait, depending on the policy.
+fn queue_policies<P: ThreadPool + Send + Sync + 'static>() -> Result<()> {
+    let done = Arc::new(AtomicUsize::new(0));
+
+    // Learn how many jobs fill a pool with one thread and room for one
+    // more, by spawning until it turns one away.
+    let pool = P::with_queue(1, 1, QueuePolicy::Reject)?;
+    let (release, gate) = channel::unbounded::<()>();
+    let mut full = 0;
+    loop {
+        match pool.try_spawn(gated(&gate, &done)) {
+            Ok(()) => full += 1,
+            Err(KvError::Busy) => break,
+            Err(err) => panic!("unexpected error {}", err),
+        }
+        assert!(full < 10, "a full queue should turn jobs away");
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(release);
+    drop(pool);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(done.load(Ordering::SeqCst), full);
+
+    let pool = P::with_queue(1, 1, QueuePolicy::CallerRuns)?;
+    let (release, gate) = channel::unbounded::<()>();
+    for _ in 0..full {
+        pool.try_spawn(gated(&gate, &done))?;
+        thread::sleep(Duration::from_millis(10));
+    }
+    let caller = thread::current().id();
+    let ran_on = Arc::new(Mutex::new(None));
+    {
+        let ran_on = Arc::clone(&ran_on);
+        pool.try_spawn(move || *ran_on.lock().unwrap() = Some(thread::current().id()))?;
+    }
+    assert_eq!(*ran_on.lock().unwrap(), Some(caller));
+    drop(release);
+
+    let pool = Arc::new(P::with_queue(1, 1, QueuePolicy::Block)?);
+    let (release, gate) = channel::unbounded::<()>();
+    for _ in 0..full {
+        pool.try_spawn(gated(&gate, &done))?;
+        thread::sleep(Duration::from_millis(10));
+    }
+    let spawned = Arc::new(AtomicUsize::new(0));
+    let spawner = {
+        let pool = Arc::clone(&pool);
+        let spawned = Arc::clone(&spawned);
+        thread::spawn(move || {
+            pool.spawn(|| ());
+            spawned.fetch_add(1, Ordering::SeqCst);
+        })
+    };
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(spawned.load(Ordering::SeqCst), 0);
+    drop(release);
+    spawner.join().unwrap();
+    assert_eq!(spawned.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_queue_policies() -> Result<()> {
+    queue_policies::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_queue_policies() -> Result<()> {
+    queue_policies::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_queue_policies() -> Result<()> {
+    queue_policies::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_scope() -> Result<()> {
+    scope::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_queue_policies() -> Result<()> {
+    queue_policies::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_drop_drains_queue() -> Result<()> {
+    drop_drains_queue::<WorkStealingThreadPool>()
+}
+
+// A pool without threads could never run a job, so none of them can be
+// made.
+fn zero_threads<P: ThreadPool>() {
+    assert!(P::new(0).is_err());
+    assert!(P::with_queue(0, 1, QueuePolicy::Block).is_err());
+}
+
+#[test]
+fn shared_queue_thread_pool_zero_threads() {
+    zero_threads::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_zero_threads() {
+    zero_threads::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_zero_threads() {
+    zero_threads::<WorkStealingThreadPool>()
+}